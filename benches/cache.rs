@@ -8,7 +8,7 @@ use trust_dns_proto::{
 };
 
 async fn cache() {
-    let cache = &Cache::new();
+    let cache = &Cache::default();
 
     let mut handle_list = Vec::new();
     for i in 0..10000 {
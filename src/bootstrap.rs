@@ -1,115 +1,339 @@
-use crate::error::UpstreamError::{self, Bootstrap, Build};
-use crate::utils::build_request_message;
+use crate::error::UpstreamError::{self, Bootstrap, Build, InvalidBootstrap};
+use crate::utils::{build_request_message, DNS_MESSAGE_CONTENT_TYPE};
+use futures::join;
 use http::header::{ACCEPT, CONTENT_TYPE};
 use reqwest::{
     header::{HeaderMap, HeaderValue},
-    Client,
+    Client, Url,
 };
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::warn;
 use trust_dns_proto::{
     op::message::Message,
-    rr::{Name, RData, RecordType},
+    rr::{Name, RData, Record, RecordType},
 };
 
+const MAX_CNAME_QUERIES: usize = 8;
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(10);
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+#[derive(Clone, Debug)]
+enum BootstrapServer {
+    Https(Url),
+    Udp(SocketAddr),
+}
+
+impl fmt::Display for BootstrapServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootstrapServer::Https(url) => write!(f, "{}", url),
+            BootstrapServer::Udp(socket_addr) => write!(f, "udp://{}", socket_addr),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct BootstrapClient {
     https_client: Client,
+    server_list: Vec<BootstrapServer>,
 }
 
 impl BootstrapClient {
-    pub fn new() -> Result<Self, UpstreamError> {
+    pub fn new(url_list: Vec<Url>) -> Result<Self, UpstreamError> {
+        let mut server_list = Vec::new();
+        for url in url_list {
+            // the bootstrap servers can't depend on DNS themselves, so only IP hosts are allowed
+            let host = url.host_str().unwrap_or_default();
+            let ip_address = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+                Ok(ip_address) => ip_address,
+                Err(_) => return Err(InvalidBootstrap(url.to_string())),
+            };
+
+            match url.scheme() {
+                "https" => server_list.push(BootstrapServer::Https(url)),
+                "udp" => {
+                    let port = url.port().unwrap_or(53);
+                    let socket_addr = SocketAddr::new(ip_address, port);
+                    server_list.push(BootstrapServer::Udp(socket_addr));
+                }
+                _ => return Err(InvalidBootstrap(url.to_string())),
+            }
+        }
+
         let mut headers = HeaderMap::new();
         headers.insert(
             CONTENT_TYPE,
-            HeaderValue::from_str("application/dns-message").unwrap(),
-        );
-        headers.insert(
-            ACCEPT,
-            HeaderValue::from_str("application/dns-message").unwrap(),
+            HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE),
         );
+        headers.insert(ACCEPT, HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE));
 
         let client_builder = Client::builder()
             .default_headers(headers)
             .https_only(true)
             .gzip(true)
             .brotli(true)
-            .timeout(Duration::from_secs(10));
+            .timeout(BOOTSTRAP_TIMEOUT);
 
         let https_client = match client_builder.build() {
             Ok(https_client) => https_client,
             Err(_) => return Err(Build),
         };
 
-        Ok(BootstrapClient { https_client })
+        Ok(BootstrapClient {
+            https_client,
+            server_list,
+        })
     }
 
-    pub async fn bootstrap(&self, host: &str) -> Result<SocketAddr, UpstreamError> {
+    pub async fn bootstrap(
+        &self,
+        host: &str,
+    ) -> Result<(Vec<SocketAddr>, Duration), UpstreamError> {
         let request_name = match host.parse::<Name>() {
             Ok(request_name) => request_name,
             Err(error) => return Err(Bootstrap(host.to_string(), error.to_string())),
         };
-        let request_message = build_request_message(request_name, RecordType::A);
 
+        let (ipv6_result, ipv4_result) = join!(
+            self.lookup(request_name.clone(), RecordType::AAAA),
+            self.lookup(request_name, RecordType::A),
+        );
+        let ((ipv6_address_list, ipv6_ttl), (ipv4_address_list, ipv4_ttl)) =
+            match (ipv6_result, ipv4_result) {
+                (Err(error), Err(_)) => return Err(Bootstrap(host.to_string(), error)),
+                (ipv6_result, ipv4_result) => (
+                    ipv6_result.unwrap_or((Vec::new(), u32::MAX)),
+                    ipv4_result.unwrap_or((Vec::new(), u32::MAX)),
+                ),
+            };
+        let ttl = Duration::from_secs(ipv6_ttl.min(ipv4_ttl).into());
+
+        // the address families are interleaved so that the connection attempts
+        // alternate between IPv6 and IPv4 as RFC 8305 suggests
+        let mut socket_addr_list = Vec::new();
+        let mut ipv6_address_iter = ipv6_address_list.into_iter();
+        let mut ipv4_address_iter = ipv4_address_list.into_iter();
+        loop {
+            let ipv6_address = ipv6_address_iter.next();
+            let ipv4_address = ipv4_address_iter.next();
+            if ipv6_address.is_none() && ipv4_address.is_none() {
+                break;
+            }
+            socket_addr_list.extend(
+                ipv6_address
+                    .into_iter()
+                    .chain(ipv4_address)
+                    .map(|ip_address| SocketAddr::new(ip_address, 0)),
+            );
+        }
+
+        if socket_addr_list.is_empty() {
+            return Err(Bootstrap(
+                host.to_string(),
+                String::from("the response doesn't contain the answer"),
+            ));
+        }
+        Ok((socket_addr_list, ttl))
+    }
+
+    async fn lookup(
+        &self,
+        mut request_name: Name,
+        record_type: RecordType,
+    ) -> Result<(Vec<IpAddr>, u32), String> {
+        let mut ttl = u32::MAX;
+        for _ in 0..MAX_CNAME_QUERIES {
+            let response_message = self.query(request_name.clone(), record_type).await?;
+            let (ip_address_list, target_name) =
+                follow_answers(&request_name, response_message.answers());
+            if let Some(min_ttl) = response_message
+                .answers()
+                .iter()
+                .map(|record| record.ttl())
+                .min()
+            {
+                ttl = ttl.min(min_ttl);
+            }
+
+            if !ip_address_list.is_empty() {
+                return Ok((ip_address_list, ttl));
+            }
+            if target_name == request_name {
+                return Ok((ip_address_list, u32::MAX));
+            }
+
+            // the CNAME chain is incomplete, so the query is repeated for its target
+            request_name = target_name;
+        }
+        Err(String::from("the CNAME chain is too long"))
+    }
+
+    async fn query(&self, request_name: Name, record_type: RecordType) -> Result<Message, String> {
+        let request_message = build_request_message(request_name, record_type);
         let raw_request_message = match request_message.to_vec() {
             Ok(raw_request_message) => raw_request_message,
-            Err(error) => return Err(Bootstrap(host.to_string(), error.to_string())),
+            Err(error) => return Err(error.to_string()),
         };
 
-        let url = "https://1.1.1.1/dns-query";
-        let request = self.https_client.post(url).body(raw_request_message);
+        let mut last_error = String::from("no bootstrap server is configured");
+        for server in self.server_list.iter() {
+            let result = match server {
+                BootstrapServer::Https(url) => {
+                    self.query_https(url, raw_request_message.clone()).await
+                }
+                BootstrapServer::Udp(socket_addr) => {
+                    query_udp(*socket_addr, &raw_request_message, request_message.id()).await
+                }
+            };
+
+            match result {
+                Ok(response_message) => return Ok(response_message),
+                Err(error) => {
+                    warn!("failed to query the bootstrap server {}: {}", server, error);
+                    last_error = error;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn query_https(
+        &self,
+        url: &Url,
+        raw_request_message: Vec<u8>,
+    ) -> Result<Message, String> {
+        let request = self
+            .https_client
+            .post(url.clone())
+            .body(raw_request_message);
         let response = match request.send().await {
             Ok(response) => response,
-            Err(error) => return Err(Bootstrap(host.to_string(), error.to_string())),
+            Err(error) => return Err(error.to_string()),
         };
 
         let raw_response_message = match response.bytes().await {
             Ok(response_bytes) => response_bytes,
-            Err(error) => return Err(Bootstrap(host.to_string(), error.to_string())),
+            Err(error) => return Err(error.to_string()),
         };
 
-        let response_message = match Message::from_vec(&raw_response_message) {
-            Ok(response_message) => response_message,
-            Err(error) => return Err(Bootstrap(host.to_string(), error.to_string())),
-        };
-
-        if response_message.answers().is_empty() {
-            return Err(Bootstrap(
-                host.to_string(),
-                String::from("the response doesn't contain the answer"),
-            ));
+        match Message::from_vec(&raw_response_message) {
+            Ok(response_message) => Ok(response_message),
+            Err(error) => Err(error.to_string()),
         }
-        let record = &response_message.answers()[0];
-        let record_data = match record.data() {
-            Some(record_data) => record_data,
-            None => {
-                return Err(Bootstrap(
-                    host.to_string(),
-                    String::from("the response doesn't contain the answer"),
-                ))
+    }
+}
+
+async fn query_udp(
+    socket_addr: SocketAddr,
+    raw_request_message: &[u8],
+    message_id: u16,
+) -> Result<Message, String> {
+    let local_addr = match socket_addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let udp_socket = match UdpSocket::bind(local_addr).await {
+        Ok(udp_socket) => udp_socket,
+        Err(error) => return Err(error.to_string()),
+    };
+    if let Err(error) = udp_socket.connect(socket_addr).await {
+        return Err(error.to_string());
+    }
+    if let Err(error) = udp_socket.send(raw_request_message).await {
+        return Err(error.to_string());
+    }
+
+    let receive = async {
+        let mut buffer = [0; 4096];
+        loop {
+            let length = match udp_socket.recv(&mut buffer).await {
+                Ok(length) => length,
+                Err(error) => return Err(error.to_string()),
+            };
+
+            // the datagrams that don't answer this request are ignored
+            match Message::from_vec(&buffer[..length]) {
+                Ok(response_message) if response_message.id() == message_id => {
+                    return Ok(response_message)
+                }
+                _ => continue,
             }
-        };
+        }
+    };
+    match timeout(BOOTSTRAP_TIMEOUT, receive).await {
+        Ok(result) => result,
+        Err(_) => Err(String::from("the request timed out")),
+    }
+}
 
-        match record_data {
-            RData::A(ipv4_address) => Ok(SocketAddr::new((*ipv4_address).into(), 0)),
-            RData::AAAA(ipv6_address) => Ok(SocketAddr::new((*ipv6_address).into(), 0)),
-            _ => Err(Bootstrap(
-                host.to_string(),
-                String::from("unknown record type"),
-            )),
+pub fn read_resolv_conf() -> Vec<Url> {
+    match fs::read_to_string(RESOLV_CONF_PATH) {
+        Ok(resolv_conf) => parse_resolv_conf(&resolv_conf),
+        Err(error) => {
+            warn!("failed to read {}: {}", RESOLV_CONF_PATH, error);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_resolv_conf(resolv_conf: &str) -> Vec<Url> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .filter_map(|ip_address| {
+            let url = match ip_address {
+                IpAddr::V4(ipv4_address) => format!("udp://{}:53", ipv4_address),
+                IpAddr::V6(ipv6_address) => format!("udp://[{}]:53", ipv6_address),
+            };
+            url.parse().ok()
+        })
+        .collect()
+}
+
+fn follow_answers(request_name: &Name, answers: &[Record]) -> (Vec<IpAddr>, Name) {
+    let mut target_name = request_name.clone();
+    for _ in 0..answers.len() {
+        let cname = answers.iter().find_map(|record| match record.data() {
+            Some(RData::CNAME(cname)) if record.name() == &target_name => Some(cname.clone()),
+            _ => None,
+        });
+        match cname {
+            Some(cname) => target_name = cname,
+            None => break,
         }
     }
+
+    let ip_address_list = answers
+        .iter()
+        .filter(|record| record.name() == &target_name)
+        .filter_map(|record| match record.data() {
+            Some(RData::A(ipv4_address)) => Some(IpAddr::from(*ipv4_address)),
+            Some(RData::AAAA(ipv6_address)) => Some(IpAddr::from(*ipv6_address)),
+            _ => None,
+        })
+        .collect();
+    (ip_address_list, target_name)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::BootstrapClient;
+    use super::{follow_answers, parse_resolv_conf, BootstrapClient};
     use std::{
         collections::HashMap,
-        net::{Ipv4Addr, SocketAddr},
+        net::{IpAddr, Ipv4Addr, SocketAddr},
     };
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
 
     #[tokio::test]
     async fn test_bootstrap() {
-        let bootstrap_client = BootstrapClient::new().unwrap();
+        let bootstrap_client =
+            BootstrapClient::new(vec!["https://1.1.1.1/dns-query".parse().unwrap()]).unwrap();
         let bootstrap_result_map = HashMap::from([
             (
                 "dns.google",
@@ -142,8 +366,63 @@ mod tests {
         ]);
 
         for (host, socket_addr_list) in bootstrap_result_map {
-            let result = bootstrap_client.bootstrap(host).await.unwrap();
-            assert!(socket_addr_list.contains(&result));
+            let (result, _) = bootstrap_client.bootstrap(host).await.unwrap();
+            assert!(socket_addr_list.iter().all(|addr| result.contains(addr)));
+        }
+    }
+
+    #[test]
+    fn test_follow_answers() {
+        let name: Name = "www.example.com".parse().unwrap();
+        let alias: Name = "example.net".parse().unwrap();
+
+        let mut cname = Record::with(name.clone(), RecordType::CNAME, 300);
+        cname.set_data(Some(RData::CNAME(alias.clone())));
+        let mut answer_list = vec![cname];
+        for i in 1..=2 {
+            let mut answer = Record::with(alias.clone(), RecordType::A, 300);
+            answer.set_data(Some(RData::A(Ipv4Addr::new(10, 0, 0, i))));
+            answer_list.push(answer);
+        }
+
+        let (ip_address_list, target_name) = follow_answers(&name, &answer_list);
+        assert_eq!(target_name, alias);
+        assert_eq!(
+            ip_address_list,
+            vec![
+                IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)),
+                IpAddr::from(Ipv4Addr::new(10, 0, 0, 2))
+            ]
+        );
+
+        let (ip_address_list, target_name) = follow_answers(&name, &answer_list[..1]);
+        assert_eq!(target_name, alias);
+        assert!(ip_address_list.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_bootstrap() {
+        for url in [
+            "https://cloudflare-dns.com/dns-query",
+            "udp://dns.google:53",
+            "tcp://8.8.8.8:53",
+        ] {
+            assert!(BootstrapClient::new(vec![url.parse().unwrap()]).is_err());
         }
+        assert!(BootstrapClient::new(vec![
+            "udp://8.8.8.8".parse().unwrap(),
+            "udp://[2001:4860:4860::8888]:53".parse().unwrap(),
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let resolv_conf = "# generated\nsearch example.com\nnameserver 192.168.1.1\nnameserver ::1\nnameserver fe80::1%eth0\n";
+        let url_list: Vec<String> = parse_resolv_conf(resolv_conf)
+            .into_iter()
+            .map(|url| url.to_string())
+            .collect();
+        assert_eq!(url_list, vec!["udp://192.168.1.1:53", "udp://[::1]:53"]);
     }
 }
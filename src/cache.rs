@@ -5,29 +5,59 @@ use std::{
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use trust_dns_proto::op::{message::Message, Query};
+use tracing::debug;
+use trust_dns_proto::{
+    op::{message::Message, Query, ResponseCode},
+    rr::{RData, Record},
+};
 
 #[derive(Debug, Hash, PartialEq, Eq)]
 struct Key {
     query: Query,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Positive,
+    Negative,
+}
+
 #[derive(Debug)]
 struct Value {
     message: Message,
+    kind: Kind,
     instant: Instant,
     ttl: Duration,
+    hits: u64,
+    prefetching: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct Cache {
     lru_cache: Arc<Mutex<LruCache<Key, Value>>>,
+    max_negative_ttl: Duration,
+    stale_ttl: Duration,
+    prefetch_hits: u64,
+    prefetch_ratio: f64,
 }
 
+// RFC 8767 recommends 30 seconds as the TTL of the stale records
+const STALE_RECORD_TTL: u32 = 30;
+
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(
+        capacity: usize,
+        max_negative_ttl: Duration,
+        stale_ttl: Duration,
+        prefetch_hits: u64,
+        prefetch_ratio: f64,
+    ) -> Self {
         Cache {
-            lru_cache: Arc::new(Mutex::new(LruCache::new(1024))),
+            lru_cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            max_negative_ttl,
+            stale_ttl,
+            prefetch_hits,
+            prefetch_ratio,
         }
     }
 
@@ -36,28 +66,56 @@ impl Cache {
             return;
         }
 
+        let (kind, ttl) = match message.response_code() {
+            ResponseCode::NoError if !message.answers().is_empty() => {
+                let min_ttl = message
+                    .answers()
+                    .iter()
+                    .map(|record| record.ttl())
+                    .min()
+                    .unwrap_or_default();
+                (Kind::Positive, Duration::from_secs(min_ttl.into()))
+            }
+            // RFC 2308 caches NXDOMAIN and NODATA responses for the lesser of
+            // the SOA TTL and the SOA minimum field
+            ResponseCode::NoError | ResponseCode::NXDomain => {
+                let negative_ttl =
+                    message
+                        .name_servers()
+                        .iter()
+                        .find_map(|record| match record.data() {
+                            Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+                            _ => None,
+                        });
+                match negative_ttl {
+                    Some(negative_ttl) => (
+                        Kind::Negative,
+                        Duration::from_secs(negative_ttl.into()).min(self.max_negative_ttl),
+                    ),
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+
         let query = message.queries()[0].clone();
         let key = Key { query };
-
-        if let Some(min_record) = message
-            .answers()
-            .iter()
-            .min_by(|record_1, record_2| record_1.ttl().cmp(&record_2.ttl()))
-        {
-            let value = Value {
-                ttl: Duration::from_secs(min_record.ttl().into()),
-                instant: Instant::now(),
-                message,
-            };
-
-            let mut lru_cache = self.lru_cache.lock().unwrap();
-            lru_cache.put(key, value);
+        let value = Value {
+            message,
+            kind,
+            instant: Instant::now(),
+            ttl,
+            hits: 0,
+            prefetching: false,
         };
+
+        let mut lru_cache = self.lru_cache.lock().unwrap();
+        lru_cache.put(key, value);
     }
 
     pub fn get(&mut self, message: &Message) -> Option<Message> {
         let mut lru_cache = self.lru_cache.lock().unwrap();
-        if lru_cache.len() == 0 || message.queries().is_empty() {
+        if lru_cache.is_empty() || message.queries().is_empty() {
             return None;
         }
 
@@ -65,45 +123,182 @@ impl Cache {
         let query = message.queries()[0].clone();
         let cache_key = Key { query };
 
-        let cache_value = match lru_cache.get(&cache_key) {
+        let cache_value = match lru_cache.get_mut(&cache_key) {
             Some(cache_value) => cache_value,
             None => {
                 return None;
             }
         };
 
-        let instant = cache_value.instant;
+        let elapsed = cache_value.instant.elapsed();
         let ttl = cache_value.ttl;
         let mut message = cache_value.message.clone();
 
-        if instant.elapsed() < ttl {
+        if elapsed < ttl {
+            debug!(kind = ?cache_value.kind, "cache hit");
+            cache_value.hits += 1;
+            let elapsed_secs = elapsed.as_secs() as u32;
+            decrement_ttl(message.answers_mut(), elapsed_secs);
+            decrement_ttl(message.name_servers_mut(), elapsed_secs);
+            decrement_ttl(message.additionals_mut(), elapsed_secs);
+
             message.set_id(message_id);
             Some(message)
         } else {
-            lru_cache.pop(&cache_key);
+            if elapsed >= ttl + self.stale_ttl {
+                lru_cache.pop(&cache_key);
+            }
             None
         }
     }
+
+    pub fn prefetch(&mut self, message: &Message) -> bool {
+        if self.prefetch_hits == 0 || message.queries().is_empty() {
+            return false;
+        }
+
+        let mut lru_cache = self.lru_cache.lock().unwrap();
+        let query = message.queries()[0].clone();
+        let cache_key = Key { query };
+
+        let cache_value = match lru_cache.peek_mut(&cache_key) {
+            Some(cache_value) => cache_value,
+            None => {
+                return false;
+            }
+        };
+
+        if cache_value.prefetching
+            || cache_value.hits < self.prefetch_hits
+            || cache_value.instant.elapsed() < cache_value.ttl.mul_f64(self.prefetch_ratio)
+        {
+            return false;
+        }
+
+        cache_value.prefetching = true;
+        true
+    }
+
+    // the stale responses share the prefetching flag, so that a single refresh
+    // is in flight for each entry
+    pub fn start_refresh(&mut self, message: &Message) -> bool {
+        if message.queries().is_empty() {
+            return false;
+        }
+
+        let mut lru_cache = self.lru_cache.lock().unwrap();
+        let query = message.queries()[0].clone();
+        let cache_key = Key { query };
+
+        match lru_cache.peek_mut(&cache_key) {
+            Some(cache_value) if !cache_value.prefetching => {
+                cache_value.prefetching = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn prefetch_done(&mut self, message: &Message) {
+        if message.queries().is_empty() {
+            return;
+        }
+
+        let mut lru_cache = self.lru_cache.lock().unwrap();
+        let query = message.queries()[0].clone();
+        let cache_key = Key { query };
+
+        if let Some(cache_value) = lru_cache.peek_mut(&cache_key) {
+            cache_value.prefetching = false;
+        }
+    }
+
+    pub fn get_stale(&mut self, message: &Message) -> Option<Message> {
+        let mut lru_cache = self.lru_cache.lock().unwrap();
+        if lru_cache.is_empty() || message.queries().is_empty() {
+            return None;
+        }
+
+        let message_id = message.id();
+        let query = message.queries()[0].clone();
+        let cache_key = Key { query };
+
+        let cache_value = match lru_cache.get(&cache_key) {
+            Some(cache_value) => cache_value,
+            None => {
+                return None;
+            }
+        };
+
+        if cache_value.instant.elapsed() >= cache_value.ttl + self.stale_ttl {
+            lru_cache.pop(&cache_key);
+            return None;
+        }
+
+        let mut message = cache_value.message.clone();
+        set_ttl(message.answers_mut(), STALE_RECORD_TTL);
+        set_ttl(message.name_servers_mut(), STALE_RECORD_TTL);
+        set_ttl(message.additionals_mut(), STALE_RECORD_TTL);
+
+        message.set_id(message_id);
+        Some(message)
+    }
+}
+
+fn decrement_ttl(record_list: &mut [Record], elapsed_secs: u32) {
+    for record in record_list {
+        let ttl = record.ttl().saturating_sub(elapsed_secs);
+        record.set_ttl(ttl);
+    }
+}
+
+fn set_ttl(record_list: &mut [Record], ttl: u32) {
+    for record in record_list {
+        record.set_ttl(ttl);
+    }
 }
 
 impl Default for Cache {
     fn default() -> Self {
-        Self::new()
+        Self::new(1024, Duration::from_secs(3600), Duration::ZERO, 0, 0.9)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Cache;
-    use std::net::Ipv4Addr;
+    use super::{Cache, Key, Kind, Value};
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
     use trust_dns_proto::{
-        op::{message::Message, Query},
-        rr::{Name, RData, Record, RecordType},
+        op::{message::Message, Query, ResponseCode},
+        rr::{rdata::SOA, Name, RData, Record, RecordType},
     };
 
+    fn build_negative_message(query: Query, soa_ttl: u32, soa_minimum: u32) -> Message {
+        let zone: Name = "example.com".parse().unwrap();
+        let mut authority = Record::with(zone.clone(), RecordType::SOA, soa_ttl);
+        authority.set_data(Some(RData::SOA(SOA::new(
+            zone.clone(),
+            zone,
+            1,
+            7200,
+            3600,
+            1209600,
+            soa_minimum,
+        ))));
+
+        let mut response_message = Message::new();
+        response_message.add_query(query);
+        response_message.add_name_server(authority);
+        response_message.set_response_code(ResponseCode::NXDomain);
+        response_message
+    }
+
     #[test]
     fn test_cache_hit() {
-        let mut cache = Cache::new();
+        let mut cache = Cache::default();
         let mut query = Query::new();
         let name: Name = "example.com".parse().unwrap();
         query.set_name(name.clone());
@@ -124,7 +319,146 @@ mod tests {
     #[test]
     #[should_panic]
     fn test_cache_expire() {
-        let mut cache = Cache::new();
+        let mut cache = Cache::default();
+        let mut query = Query::new();
+        let name: Name = "example.com".parse().unwrap();
+        query.set_name(name.clone());
+
+        let mut answer = Record::with(name, RecordType::A, 0);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+
+        let mut response_message = Message::new();
+        response_message.add_query(query.clone());
+        response_message.add_answer(answer);
+        cache.put(response_message);
+
+        let mut request_message = Message::new();
+        request_message.add_query(query);
+        cache.get(&request_message).unwrap();
+    }
+
+    #[test]
+    fn test_cache_decrement_ttl() {
+        let cache = Cache::default();
+        let mut query = Query::new();
+        let name: Name = "example.com".parse().unwrap();
+        query.set_name(name.clone());
+
+        let mut answer = Record::with(name.clone(), RecordType::A, 3000);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+        let mut additional = Record::with(name, RecordType::A, 3600);
+        additional.set_data(Some(RData::A(Ipv4Addr::new(1, 0, 0, 1))));
+
+        let mut response_message = Message::new();
+        response_message.add_query(query.clone());
+        response_message.add_answer(answer);
+        response_message.add_additional(additional);
+
+        let value = Value {
+            message: response_message,
+            kind: Kind::Positive,
+            instant: Instant::now() - Duration::from_secs(2900),
+            ttl: Duration::from_secs(3000),
+            hits: 0,
+            prefetching: false,
+        };
+        cache.lru_cache.lock().unwrap().put(
+            Key {
+                query: query.clone(),
+            },
+            value,
+        );
+
+        let mut request_message = Message::new();
+        request_message.add_query(query);
+        let response_message = cache.clone().get(&request_message).unwrap();
+        assert_eq!(response_message.answers()[0].ttl(), 100);
+        assert_eq!(response_message.additionals()[0].ttl(), 700);
+    }
+
+    #[test]
+    fn test_cache_negative() {
+        let mut cache = Cache::default();
+        let mut query = Query::new();
+        let name: Name = "nonexistent.example.com".parse().unwrap();
+        query.set_name(name);
+
+        cache.put(build_negative_message(query.clone(), 3600, 300));
+
+        let mut request_message = Message::new();
+        request_message.add_query(query.clone());
+        let response_message = cache.get(&request_message).unwrap();
+        assert_eq!(response_message.response_code(), ResponseCode::NXDomain);
+
+        let lru_cache = cache.lru_cache.lock().unwrap();
+        let value = lru_cache.peek(&Key { query }).unwrap();
+        assert_eq!(value.kind, Kind::Negative);
+        assert_eq!(value.ttl, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_cache_negative_cap() {
+        let mut cache = Cache::new(1024, Duration::from_secs(60), Duration::ZERO, 0, 0.9);
+        let mut query = Query::new();
+        let name: Name = "nonexistent.example.com".parse().unwrap();
+        query.set_name(name);
+
+        cache.put(build_negative_message(query.clone(), 86400, 86400));
+
+        let lru_cache = cache.lru_cache.lock().unwrap();
+        let value = lru_cache.peek(&Key { query }).unwrap();
+        assert_eq!(value.ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_cache_negative_without_soa() {
+        let mut cache = Cache::default();
+        let mut query = Query::new();
+        let name: Name = "nonexistent.example.com".parse().unwrap();
+        query.set_name(name);
+
+        let mut response_message = Message::new();
+        response_message.add_query(query.clone());
+        response_message.set_response_code(ResponseCode::NXDomain);
+        cache.put(response_message);
+
+        let mut request_message = Message::new();
+        request_message.add_query(query);
+        assert!(cache.get(&request_message).is_none());
+    }
+
+    #[test]
+    fn test_cache_stale() {
+        let mut cache = Cache::new(
+            1024,
+            Duration::from_secs(3600),
+            Duration::from_secs(86400),
+            0,
+            0.9,
+        );
+        let mut query = Query::new();
+        let name: Name = "example.com".parse().unwrap();
+        query.set_name(name.clone());
+
+        let mut answer = Record::with(name, RecordType::A, 0);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+
+        let mut response_message = Message::new();
+        response_message.add_query(query.clone());
+        response_message.add_answer(answer);
+        cache.put(response_message);
+
+        let mut request_message = Message::new();
+        request_message.add_query(query);
+        assert!(cache.get(&request_message).is_none());
+
+        let response_message = cache.get_stale(&request_message).unwrap();
+        assert_eq!(response_message.answers()[0].ttl(), 30);
+    }
+
+    #[test]
+    fn test_cache_stale_disabled() {
+        let mut cache = Cache::default();
         let mut query = Query::new();
         let name: Name = "example.com".parse().unwrap();
         query.set_name(name.clone());
@@ -139,6 +473,99 @@ mod tests {
 
         let mut request_message = Message::new();
         request_message.add_query(query);
+        assert!(cache.get_stale(&request_message).is_none());
+    }
+
+    #[test]
+    fn test_cache_prefetch() {
+        let mut cache = Cache::new(1024, Duration::from_secs(3600), Duration::ZERO, 2, 0.9);
+        let mut query = Query::new();
+        let name: Name = "example.com".parse().unwrap();
+        query.set_name(name.clone());
+
+        let mut answer = Record::with(name, RecordType::A, 1000);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+
+        let mut response_message = Message::new();
+        response_message.add_query(query.clone());
+        response_message.add_answer(answer);
+
+        let value = Value {
+            message: response_message,
+            kind: Kind::Positive,
+            instant: Instant::now() - Duration::from_secs(950),
+            ttl: Duration::from_secs(1000),
+            hits: 0,
+            prefetching: false,
+        };
+        cache.lru_cache.lock().unwrap().put(
+            Key {
+                query: query.clone(),
+            },
+            value,
+        );
+
+        let mut request_message = Message::new();
+        request_message.add_query(query);
+
+        cache.get(&request_message).unwrap();
+        assert!(!cache.prefetch(&request_message));
+
+        cache.get(&request_message).unwrap();
+        assert!(cache.prefetch(&request_message));
+
         cache.get(&request_message).unwrap();
+        assert!(!cache.prefetch(&request_message));
+
+        cache.prefetch_done(&request_message);
+        assert!(cache.prefetch(&request_message));
+    }
+
+    #[test]
+    fn test_cache_skip_error() {
+        let mut cache = Cache::default();
+        let mut query = Query::new();
+        let name: Name = "example.com".parse().unwrap();
+        query.set_name(name.clone());
+
+        let mut answer = Record::with(name, RecordType::A, 1000);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+
+        let mut response_message = Message::new();
+        response_message.add_query(query.clone());
+        response_message.add_answer(answer);
+        response_message.set_response_code(ResponseCode::ServFail);
+        cache.put(response_message);
+
+        let mut request_message = Message::new();
+        request_message.add_query(query);
+        assert!(cache.get(&request_message).is_none());
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let mut cache = Cache::new(1, Duration::from_secs(3600), Duration::ZERO, 0, 0.9);
+        let mut request_message_list = Vec::new();
+
+        for host in ["example.com", "example.org"] {
+            let mut query = Query::new();
+            let name: Name = host.parse().unwrap();
+            query.set_name(name.clone());
+
+            let mut answer = Record::with(name, RecordType::A, 1000);
+            answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+
+            let mut response_message = Message::new();
+            response_message.add_query(query.clone());
+            response_message.add_answer(answer);
+            cache.put(response_message);
+
+            let mut request_message = Message::new();
+            request_message.add_query(query);
+            request_message_list.push(request_message);
+        }
+
+        assert!(cache.get(&request_message_list[0]).is_none());
+        assert!(cache.get(&request_message_list[1]).is_some());
     }
 }
@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{ArgEnum, Parser};
+use reqwest::Url;
 
 #[derive(Parser, Debug)]
 #[clap(version, about)]
@@ -9,9 +10,120 @@ pub struct Args {
     #[clap(long, default_value = "53")]
     pub local_port: u16,
 
-    #[clap(long, default_value = "1.1.1.1")]
-    pub upstream_address: String,
+    #[clap(long, default_value = "10")]
+    pub tcp_idle_timeout: u64,
 
-    #[clap(long, default_value = "443")]
-    pub upstream_port: u16,
+    #[clap(long, requires_all = &["tls-certificate", "tls-key"])]
+    pub local_https_port: Option<u16>,
+
+    #[clap(long, requires_all = &["tls-certificate", "tls-key"])]
+    pub local_tls_port: Option<u16>,
+
+    #[clap(long)]
+    pub tls_certificate: Option<String>,
+
+    #[clap(long)]
+    pub tls_key: Option<String>,
+
+    #[clap(
+        long,
+        default_value = "https://1.1.1.1/dns-query",
+        multiple_occurrences = true
+    )]
+    pub upstream: Vec<Url>,
+
+    #[clap(long, default_value = "10")]
+    pub upstream_timeout: u64,
+
+    #[clap(long, default_value = "90")]
+    pub upstream_pool_idle_timeout: u64,
+
+    #[clap(long, default_value = "0")]
+    pub upstream_keepalive_interval: u64,
+
+    #[clap(long)]
+    pub upstream_warm_up: bool,
+
+    #[cfg(feature = "http3")]
+    #[clap(long)]
+    pub upstream_http3: bool,
+
+    #[clap(long, arg_enum, default_value = "failover")]
+    pub upstream_strategy: UpstreamStrategy,
+
+    #[clap(long, multiple_occurrences = true)]
+    pub upstream_weight: Vec<u32>,
+
+    #[clap(long, arg_enum, multiple_occurrences = true)]
+    pub upstream_method: Vec<UpstreamMethod>,
+
+    #[clap(long, default_value = "2")]
+    pub upstream_race_count: usize,
+
+    #[clap(
+        long,
+        default_value = "https://1.1.1.1/dns-query",
+        multiple_occurrences = true
+    )]
+    pub bootstrap: Vec<Url>,
+
+    #[clap(long)]
+    pub bootstrap_resolv_conf: bool,
+
+    #[clap(long, default_value = "1024")]
+    pub cache_size: usize,
+
+    #[clap(long)]
+    pub disable_cache: bool,
+
+    #[clap(long, default_value = "3600")]
+    pub cache_max_negative_ttl: u64,
+
+    #[clap(long, default_value = "0")]
+    pub cache_stale_ttl: u64,
+
+    #[clap(long, default_value = "0")]
+    pub cache_prefetch_hits: u64,
+
+    #[clap(long, default_value = "0.9", parse(try_from_str = parse_ratio))]
+    pub cache_prefetch_ratio: f64,
+}
+
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(String::from("the value must be a number between 0 and 1")),
+    }
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+pub enum UpstreamStrategy {
+    Failover,
+    RoundRobin,
+    Fastest,
+    Random,
+    Race,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+pub enum UpstreamMethod {
+    Get,
+    Post,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+    use clap::Parser;
+
+    #[test]
+    fn test_cache_prefetch_ratio() {
+        let args = Args::try_parse_from(["https-dns", "--cache-prefetch-ratio", "0.5"]).unwrap();
+        assert_eq!(args.cache_prefetch_ratio, 0.5);
+
+        for ratio in ["-0.1", "1.5", "nan", "invalid"] {
+            let result = Args::try_parse_from(["https-dns", "--cache-prefetch-ratio", ratio]);
+            assert!(result.is_err());
+        }
+    }
 }
@@ -1,4 +1,7 @@
+use reqwest::StatusCode;
+use std::time::Duration;
 use thiserror::Error;
+use trust_dns_proto::error::ProtoError;
 
 #[derive(Error, Debug)]
 pub enum LocalError {
@@ -10,6 +13,12 @@ pub enum LocalError {
 
     #[error("failed to bind to the address {0}:{1}")]
     Unknown(String, u16),
+
+    #[error("failed to load the TLS certificate {0}: {1}")]
+    Certificate(String, String),
+
+    #[error("failed to load the TLS key {0}: {1}")]
+    Key(String, String),
 }
 
 #[derive(Error, Debug)]
@@ -17,9 +26,53 @@ pub enum UpstreamError {
     #[error("failed to build the HTTPS client")]
     Build,
 
+    #[error("the upstream URL {0} is not a valid HTTPS, TLS or QUIC URL")]
+    InvalidUrl(String),
+
+    #[error("the option {0} is given {1} times for {2} upstreams")]
+    OptionCount(String, usize, usize),
+
+    #[error("the bootstrap URL {0} is not an HTTPS or UDP URL with an IP address")]
+    InvalidBootstrap(String),
+
     #[error("failed to bootstrap the address {0}: {1}")]
     Bootstrap(String, String),
 
-    #[error("failed to resolve the DNS request")]
-    Resolve,
+    #[error("failed to resolve the DNS request for {0}")]
+    Resolve(String),
+
+    #[error("failed to serialize the DNS request for {0}")]
+    Serialize(String, #[source] ProtoError),
+
+    #[error("the request for {0} timed out")]
+    Timeout(String, #[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("failed to connect to the upstream for {0}")]
+    Connect(String, #[source] reqwest::Error),
+
+    #[error("the upstream responded to {0} with the status {1}: {2}")]
+    Status(String, StatusCode, String),
+
+    #[error("the upstream rate limited the request for {0}: {1}")]
+    RateLimited(String, String, Option<Duration>),
+
+    #[error("the upstream responded to {0} with the content type {1}: {2}")]
+    ContentType(String, String, String),
+
+    #[error("failed to read the response body for {0}")]
+    BodyRead(String, #[source] reqwest::Error),
+
+    #[error("failed to parse the DNS response for {0}")]
+    Malformed(String, #[source] ProtoError),
+
+    #[error("failed to send the DNS-over-TLS request for {0}")]
+    Tls(String, #[source] std::io::Error),
+
+    #[cfg(feature = "http3")]
+    #[error("failed to send the HTTP/3 request for {0}")]
+    Http3(String, #[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[cfg(feature = "quic")]
+    #[error("failed to send the DNS-over-QUIC request for {0}")]
+    Quic(String, #[source] Box<dyn std::error::Error + Send + Sync>),
 }
@@ -0,0 +1,183 @@
+use crate::error::UpstreamError::{self, Http3, Timeout};
+use crate::quic::{build_quic_config, connect, BoxError};
+use crate::upstream::{build_socket_addr_list, ClientConfig};
+use crate::utils::DNS_MESSAGE_CONTENT_TYPE;
+use bytes::{Buf, Bytes};
+use h3::client::SendRequest;
+use h3_quinn::OpenStreams;
+use quinn::{Connection, VarInt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode, Url,
+};
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::debug;
+
+const HTTP3_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+const HTTP3_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const H3_NO_ERROR: u32 = 0x100;
+const HTTPS_PORT: u16 = 443;
+const H3_ALPN: &[u8] = b"h3";
+
+pub struct Http3Client {
+    server_name: String,
+    socket_addr_list: Vec<SocketAddr>,
+    quic_config: quinn::ClientConfig,
+    timeout: Duration,
+    connection: tokio::sync::Mutex<Option<(Connection, SendRequest<OpenStreams, Bytes>)>>,
+    blocked_until: Mutex<Option<Instant>>,
+}
+
+impl fmt::Debug for Http3Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Http3Client")
+            .field("server_name", &self.server_name)
+            .field("socket_addr_list", &self.socket_addr_list)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Http3Client {
+    pub fn new(
+        url: &Url,
+        socket_addr_list: &[SocketAddr],
+        client_config: &ClientConfig,
+    ) -> Result<Self, UpstreamError> {
+        let (server_name, socket_addr_list) =
+            build_socket_addr_list(url, socket_addr_list, HTTPS_PORT);
+
+        Ok(Http3Client {
+            server_name,
+            socket_addr_list,
+            quic_config: build_quic_config(H3_ALPN, client_config)?,
+            timeout: client_config.timeout,
+            connection: tokio::sync::Mutex::new(None),
+            blocked_until: Mutex::new(None),
+        })
+    }
+
+    pub fn is_available(&self) -> bool {
+        match *self.blocked_until.lock().unwrap() {
+            Some(blocked_until) => Instant::now() >= blocked_until,
+            None => true,
+        }
+    }
+
+    pub async fn send(
+        &self,
+        name: &str,
+        url: Url,
+        body: Option<Vec<u8>>,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), UpstreamError> {
+        // the handshake has a shorter timeout than the requests, since the
+        // queries wait for it before falling back to HTTP/2 on the networks
+        // that drop QUIC
+        let connect_timeout = self.timeout.min(HTTP3_CONNECT_TIMEOUT);
+        let (connection, send_request) =
+            match timeout(connect_timeout, self.get_send_request(connect_timeout)).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(error)) => {
+                    self.block();
+                    return Err(Http3(name.to_string(), error));
+                }
+                Err(elapsed) => {
+                    self.block();
+                    return Err(Timeout(name.to_string(), elapsed.into()));
+                }
+            };
+
+        let received = connection.stats().udp_rx.datagrams;
+        match timeout(self.timeout, send_request_message(send_request, url, body)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(error)) => Err(Http3(name.to_string(), error)),
+            Err(elapsed) => {
+                // the connection is shared by the other requests, so it's only
+                // closed when nothing arrived on it while this request waited
+                if connection.stats().udp_rx.datagrams == received {
+                    connection.close(VarInt::from_u32(H3_NO_ERROR), b"");
+                }
+                Err(Timeout(name.to_string(), elapsed.into()))
+            }
+        }
+    }
+
+    // QUIC is probably blocked on this network, so HTTP/3 isn't tried again
+    // until the retry interval elapses
+    fn block(&self) {
+        *self.blocked_until.lock().unwrap() = Some(Instant::now() + HTTP3_RETRY_INTERVAL);
+    }
+
+    async fn get_send_request(
+        &self,
+        connect_timeout: Duration,
+    ) -> Result<(Connection, SendRequest<OpenStreams, Bytes>), BoxError> {
+        let mut connection = self.connection.lock().await;
+        if let Some((quic_connection, send_request)) = connection.as_ref() {
+            if quic_connection.close_reason().is_none() {
+                return Ok((quic_connection.clone(), send_request.clone()));
+            }
+        }
+
+        let quic_connection = connect(
+            &self.quic_config,
+            &self.server_name,
+            &self.socket_addr_list,
+            connect_timeout,
+        )
+        .await?;
+        let (mut driver, send_request) =
+            h3::client::new(h3_quinn::Connection::new(quic_connection.clone())).await?;
+        tokio::spawn(async move {
+            let error = driver.wait_idle().await;
+            debug!("the HTTP/3 connection is closed: {}", error);
+        });
+
+        *connection = Some((quic_connection.clone(), send_request.clone()));
+        Ok((quic_connection, send_request))
+    }
+}
+
+async fn send_request_message(
+    mut send_request: SendRequest<OpenStreams, Bytes>,
+    url: Url,
+    body: Option<Vec<u8>>,
+) -> Result<(StatusCode, HeaderMap, Vec<u8>), BoxError> {
+    let mut request = http_v1::Request::builder()
+        .uri(url.as_str())
+        .header(http_v1::header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE);
+    request = match body {
+        Some(_) => request
+            .method(http_v1::Method::POST)
+            .header(http_v1::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE),
+        None => request.method(http_v1::Method::GET),
+    };
+
+    let mut stream = send_request.send_request(request.body(())?).await?;
+    if let Some(body) = body {
+        stream.send_data(Bytes::from(body)).await?;
+    }
+    stream.finish().await?;
+
+    let response = stream.recv_response().await?;
+    let mut response_body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        response_body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    // the h3 crate is built on the newer http crate, so the response is converted
+    // to the types that reqwest uses
+    let status = StatusCode::from_u16(response.status().as_u16())?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in response.headers() {
+        let name = HeaderName::from_bytes(name.as_str().as_bytes())?;
+        let value = HeaderValue::from_bytes(value.as_bytes())?;
+        headers.append(name, value);
+    }
+    Ok((status, headers, response_body))
+}
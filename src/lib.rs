@@ -2,6 +2,13 @@ pub mod bootstrap;
 pub mod cache;
 pub mod cli;
 pub mod error;
+#[cfg(feature = "http3")]
+pub mod http3;
 pub mod local;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod resolver;
+pub mod strategy;
+pub mod tls;
 pub mod upstream;
 pub mod utils;
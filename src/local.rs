@@ -1,49 +1,64 @@
-use crate::error::LocalError::{self, InvalidAddress, PermissionDenied, Unknown};
-use crate::upstream::HttpsClient;
-use std::{io, net::SocketAddr, sync::Arc};
-use tokio::net::UdpSocket;
-use tracing::{info, info_span, warn, Instrument};
+use crate::error::LocalError::{self, Certificate, InvalidAddress, Key, PermissionDenied, Unknown};
+use crate::resolver::Resolver;
+use crate::utils::{
+    build_format_error_response, build_server_failure_response, build_udp_response,
+    DNS_MESSAGE_CONTENT_TYPE,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{
+    header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderValue, Method, Request, Response, StatusCode,
+};
+use hyper::{body::HttpBody, server::conn::Http, service::service_fn, Body};
+use std::{
+    convert::Infallible, fs::File, future::Future, io, io::BufReader, net::SocketAddr, sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::{Mutex, Semaphore},
+    time::timeout,
+};
+use tokio_rustls::{rustls::ServerConfig, server::TlsStream, TlsAcceptor};
+use tracing::{debug, info, info_span, warn, Instrument};
 use trust_dns_proto::op::message::Message;
 
+const MAX_STREAM_QUERIES: usize = 100;
+
 #[derive(Debug)]
 pub struct UdpListener {
     udp_socket: Arc<UdpSocket>,
-    https_client: HttpsClient,
+    resolver: Arc<dyn Resolver>,
 }
 
 impl UdpListener {
     pub async fn new(
         host: String,
         port: u16,
-        https_client: HttpsClient,
+        resolver: Arc<dyn Resolver>,
     ) -> Result<Self, LocalError> {
-        let socket_addr: SocketAddr = match format!("{}:{}", host, port).parse() {
-            Ok(socket_addr) => socket_addr,
-            Err(_) => return Err(InvalidAddress(host, port)),
-        };
+        let socket_addr = parse_socket_addr(&host, port)?;
 
         let udp_socket = match UdpSocket::bind(socket_addr).await {
             Ok(udp_socket) => Arc::new(udp_socket),
-            Err(error) => match error.kind() {
-                io::ErrorKind::PermissionDenied => return Err(PermissionDenied(host, port)),
-                _ => return Err(Unknown(host, port)),
-            },
+            Err(error) => return Err(bind_error(error, host, port)),
         };
-        info!("listened on {}:{}", host, port);
+        info!("listened on udp://{}:{}", host, port);
 
         Ok(UdpListener {
             udp_socket,
-            https_client,
+            resolver,
         })
     }
 
     pub async fn listen(&self) {
         loop {
             let mut buffer = [0; 4096];
-            let mut https_client = self.https_client.clone();
+            let resolver = self.resolver.clone();
             let udp_socket = self.udp_socket.clone();
 
-            let (_, addr) = match udp_socket.recv_from(&mut buffer).await {
+            let (length, addr) = match udp_socket.recv_from(&mut buffer).await {
                 Ok(udp_recv_from_result) => udp_recv_from_result,
                 Err(_) => {
                     warn!("failed to receive the datagram message");
@@ -53,43 +68,28 @@ impl UdpListener {
 
             tokio::spawn(
                 async move {
-                    let request_message = match Message::from_vec(&buffer) {
-                        Ok(request_message) => request_message,
-                        Err(_) => {
-                            warn!("failed to parse the request");
-                            return;
+                    let (response_message, max_payload) = match parse_request(&buffer[..length]) {
+                        Some(request_message) => {
+                            let max_payload = request_message.max_payload();
+                            (resolve(resolver, request_message).await, max_payload)
                         }
+                        None => match build_format_error_response(&buffer[..length]) {
+                            Some(response_message) => {
+                                let max_payload = response_message.max_payload();
+                                (response_message, max_payload)
+                            }
+                            None => return,
+                        },
                     };
 
-                    for request_record in request_message.queries().iter() {
-                        info!(
-                            phase = "request",
-                            "{} {} {}",
-                            request_record.name(),
-                            request_record.query_class(),
-                            request_record.query_type(),
-                        );
-                    }
-
-                    let response_message = match https_client.process(request_message).await {
-                        Ok(response_message) => response_message,
-                        Err(error) => {
-                            warn!("{}", error);
-                            return;
-                        }
-                    };
-
-                    for response_record in response_message.answers().iter() {
-                        info!(phase = "response", "{}", response_record);
-                    }
-
-                    let raw_response_message = match response_message.to_vec() {
-                        Ok(raw_response_message) => raw_response_message,
-                        Err(_) => {
-                            warn!("failed to parse the response");
-                            return;
-                        }
-                    };
+                    let raw_response_message =
+                        match build_udp_response(&response_message, max_payload) {
+                            Ok(raw_response_message) => raw_response_message,
+                            Err(_) => {
+                                warn!("failed to parse the response");
+                                return;
+                            }
+                        };
 
                     if udp_socket
                         .send_to(&raw_response_message, &addr)
@@ -104,3 +104,442 @@ impl UdpListener {
         }
     }
 }
+
+#[derive(Debug)]
+pub struct TcpListener {
+    tcp_listener: tokio::net::TcpListener,
+    resolver: Arc<dyn Resolver>,
+    idle_timeout: Duration,
+}
+
+impl TcpListener {
+    pub async fn new(
+        host: String,
+        port: u16,
+        resolver: Arc<dyn Resolver>,
+        idle_timeout: Duration,
+    ) -> Result<Self, LocalError> {
+        let socket_addr = parse_socket_addr(&host, port)?;
+
+        let tcp_listener = match tokio::net::TcpListener::bind(socket_addr).await {
+            Ok(tcp_listener) => tcp_listener,
+            Err(error) => return Err(bind_error(error, host, port)),
+        };
+        info!("listened on tcp://{}:{}", host, port);
+
+        Ok(TcpListener {
+            tcp_listener,
+            resolver,
+            idle_timeout,
+        })
+    }
+
+    pub async fn listen(&self) {
+        loop {
+            let (tcp_stream, addr) = match self.tcp_listener.accept().await {
+                Ok(tcp_accept_result) => tcp_accept_result,
+                Err(_) => {
+                    warn!("failed to accept the TCP connection");
+                    continue;
+                }
+            };
+
+            tokio::spawn(
+                handle_stream(tcp_stream, self.resolver.clone(), self.idle_timeout)
+                    .instrument(info_span!("listen", ?addr)),
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpsListener {
+    tcp_listener: tokio::net::TcpListener,
+    tls_config: Arc<ServerConfig>,
+    resolver: Arc<dyn Resolver>,
+    idle_timeout: Duration,
+}
+
+impl HttpsListener {
+    pub async fn new(
+        host: String,
+        port: u16,
+        resolver: Arc<dyn Resolver>,
+        idle_timeout: Duration,
+        certificate_path: &str,
+        key_path: &str,
+    ) -> Result<Self, LocalError> {
+        let socket_addr = parse_socket_addr(&host, port)?;
+        let tls_config = build_tls_config(
+            certificate_path,
+            key_path,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        )?;
+
+        let tcp_listener = match tokio::net::TcpListener::bind(socket_addr).await {
+            Ok(tcp_listener) => tcp_listener,
+            Err(error) => return Err(bind_error(error, host, port)),
+        };
+        info!("listened on https://{}:{}", host, port);
+
+        Ok(HttpsListener {
+            tcp_listener,
+            tls_config,
+            resolver,
+            idle_timeout,
+        })
+    }
+
+    pub async fn listen(&self) {
+        let resolver = self.resolver.clone();
+        let idle_timeout = self.idle_timeout;
+        listen_tls(
+            &self.tcp_listener,
+            &self.tls_config,
+            idle_timeout,
+            move |tls_stream| handle_https_connection(tls_stream, resolver.clone(), idle_timeout),
+        )
+        .await;
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsListener {
+    tcp_listener: tokio::net::TcpListener,
+    tls_config: Arc<ServerConfig>,
+    resolver: Arc<dyn Resolver>,
+    idle_timeout: Duration,
+}
+
+impl TlsListener {
+    pub async fn new(
+        host: String,
+        port: u16,
+        resolver: Arc<dyn Resolver>,
+        idle_timeout: Duration,
+        certificate_path: &str,
+        key_path: &str,
+    ) -> Result<Self, LocalError> {
+        let socket_addr = parse_socket_addr(&host, port)?;
+        let tls_config = build_tls_config(certificate_path, key_path, vec![b"dot".to_vec()])?;
+
+        let tcp_listener = match tokio::net::TcpListener::bind(socket_addr).await {
+            Ok(tcp_listener) => tcp_listener,
+            Err(error) => return Err(bind_error(error, host, port)),
+        };
+        info!("listened on tls://{}:{}", host, port);
+
+        Ok(TlsListener {
+            tcp_listener,
+            tls_config,
+            resolver,
+            idle_timeout,
+        })
+    }
+
+    pub async fn listen(&self) {
+        let resolver = self.resolver.clone();
+        let idle_timeout = self.idle_timeout;
+        listen_tls(
+            &self.tcp_listener,
+            &self.tls_config,
+            idle_timeout,
+            move |tls_stream| handle_stream(tls_stream, resolver.clone(), idle_timeout),
+        )
+        .await;
+    }
+}
+
+async fn listen_tls<F, Fut>(
+    tcp_listener: &tokio::net::TcpListener,
+    tls_config: &Arc<ServerConfig>,
+    idle_timeout: Duration,
+    handle_connection: F,
+) where
+    F: Fn(TlsStream<TcpStream>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    loop {
+        let (tcp_stream, addr) = match tcp_listener.accept().await {
+            Ok(tcp_accept_result) => tcp_accept_result,
+            Err(_) => {
+                warn!("failed to accept the TCP connection");
+                continue;
+            }
+        };
+
+        let tls_acceptor = TlsAcceptor::from(tls_config.clone());
+        let handle_connection = handle_connection.clone();
+        tokio::spawn(
+            async move {
+                let tls_stream = match timeout(idle_timeout, tls_acceptor.accept(tcp_stream)).await
+                {
+                    Ok(Ok(tls_stream)) => tls_stream,
+                    _ => {
+                        warn!("failed to establish the TLS connection");
+                        return;
+                    }
+                };
+                handle_connection(tls_stream).await;
+            }
+            .instrument(info_span!("listen", ?addr)),
+        );
+    }
+}
+
+async fn handle_https_connection(
+    tls_stream: TlsStream<TcpStream>,
+    resolver: Arc<dyn Resolver>,
+    idle_timeout: Duration,
+) {
+    let service = service_fn(move |request| {
+        handle_https_request(resolver.clone(), request).in_current_span()
+    });
+
+    // the idle HTTP/1 connections time out waiting for the next request,
+    // and the HTTP/2 connections are closed once the client stops
+    // responding to the pings
+    let connection = Http::new()
+        .http1_header_read_timeout(idle_timeout)
+        .http2_keep_alive_interval(idle_timeout)
+        .http2_keep_alive_timeout(idle_timeout)
+        .serve_connection(tls_stream, service);
+    if let Err(error) = connection.await {
+        debug!("{}", error);
+    }
+}
+
+async fn handle_https_request(
+    resolver: Arc<dyn Resolver>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/dns-query" {
+        return Ok(build_https_error_response(StatusCode::NOT_FOUND));
+    }
+
+    let raw_request_message = match *request.method() {
+        Method::GET => {
+            let dns_parameter = request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|parameter| parameter.strip_prefix("dns="))
+            });
+            let dns_parameter = match dns_parameter {
+                Some(dns_parameter) => dns_parameter,
+                None => return Ok(build_https_error_response(StatusCode::BAD_REQUEST)),
+            };
+
+            match URL_SAFE_NO_PAD.decode(dns_parameter) {
+                Ok(raw_request_message) => raw_request_message,
+                Err(_) => return Ok(build_https_error_response(StatusCode::BAD_REQUEST)),
+            }
+        }
+        Method::POST => {
+            let content_type = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .unwrap_or_default();
+            let media_type = content_type.split(';').next().unwrap_or_default().trim();
+            if !media_type.eq_ignore_ascii_case(DNS_MESSAGE_CONTENT_TYPE) {
+                return Ok(build_https_error_response(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                ));
+            }
+
+            match read_https_body(request.into_body()).await {
+                Some(raw_request_message) => raw_request_message,
+                None => return Ok(build_https_error_response(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(build_https_error_response(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let request_message = match parse_request(&raw_request_message) {
+        Some(request_message) => request_message,
+        None => return Ok(build_https_error_response(StatusCode::BAD_REQUEST)),
+    };
+
+    let response_message = resolve(resolver, request_message).await;
+
+    let raw_response_message = match response_message.to_vec() {
+        Ok(raw_response_message) => raw_response_message,
+        Err(_) => {
+            warn!("failed to parse the response");
+            return Ok(build_https_error_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    let min_ttl = response_message
+        .all_sections()
+        .map(|record| record.ttl())
+        .min()
+        .unwrap_or_default();
+
+    let mut response = Response::new(Body::from(raw_response_message));
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE),
+    );
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&format!("max-age={}", min_ttl)).unwrap(),
+    );
+    Ok(response)
+}
+
+async fn read_https_body(mut body: Body) -> Option<Vec<u8>> {
+    let mut raw_body = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.ok()?;
+        if raw_body.len() + chunk.len() > u16::MAX.into() {
+            return None;
+        }
+        raw_body.extend_from_slice(&chunk);
+    }
+    Some(raw_body)
+}
+
+fn build_https_error_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from(0));
+    response
+}
+
+async fn handle_stream<S>(stream: S, resolver: Arc<dyn Resolver>, idle_timeout: Duration)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    let semaphore = Arc::new(Semaphore::new(MAX_STREAM_QUERIES));
+
+    loop {
+        // the timeout covers the whole message, so a client that stops in the
+        // middle of a message can't hold the connection open
+        let buffer = match timeout(idle_timeout, async {
+            let length = reader.read_u16().await?;
+            let mut buffer = vec![0; length.into()];
+            reader.read_exact(&mut buffer).await?;
+            Ok::<_, io::Error>(buffer)
+        })
+        .await
+        {
+            Ok(Ok(buffer)) => buffer,
+            _ => return,
+        };
+
+        // the connection stops reading once too many queries are in flight
+        let permit = match semaphore.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+
+        let resolver = resolver.clone();
+        let writer = writer.clone();
+        tokio::spawn(
+            async move {
+                let _permit = permit;
+                let response_message = match parse_request(&buffer) {
+                    Some(request_message) => resolve(resolver, request_message).await,
+                    None => match build_format_error_response(&buffer) {
+                        Some(response_message) => response_message,
+                        None => return,
+                    },
+                };
+
+                let raw_response_message = match response_message.to_vec() {
+                    Ok(raw_response_message) => raw_response_message,
+                    Err(_) => {
+                        warn!("failed to parse the response");
+                        return;
+                    }
+                };
+
+                let length = match u16::try_from(raw_response_message.len()) {
+                    Ok(length) => length,
+                    Err(_) => {
+                        warn!("the response exceeds the maximum message size");
+                        return;
+                    }
+                };
+
+                let mut writer = writer.lock().await;
+                if writer.write_u16(length).await.is_err()
+                    || writer.write_all(&raw_response_message).await.is_err()
+                {
+                    warn!("failed to send the inbound response to the client");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+fn parse_request(raw_request_message: &[u8]) -> Option<Message> {
+    match Message::from_vec(raw_request_message) {
+        Ok(request_message) => Some(request_message),
+        Err(_) => {
+            warn!("failed to parse the request");
+            None
+        }
+    }
+}
+
+async fn resolve(resolver: Arc<dyn Resolver>, request_message: Message) -> Message {
+    match resolver.resolve(&request_message).await {
+        Ok(response_message) => response_message,
+        Err(error) => {
+            warn!("{}", error);
+            build_server_failure_response(&request_message, &error)
+        }
+    }
+}
+
+fn build_tls_config(
+    certificate_path: &str,
+    key_path: &str,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<Arc<ServerConfig>, LocalError> {
+    let certificate_error = |error: String| Certificate(certificate_path.to_string(), error);
+
+    let certificate_file =
+        File::open(certificate_path).map_err(|error| certificate_error(error.to_string()))?;
+    let certificate_chain = rustls_pemfile::certs(&mut BufReader::new(certificate_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| certificate_error(error.to_string()))?;
+
+    let key_error = |error: String| Key(key_path.to_string(), error);
+    let key_file = File::open(key_path).map_err(|error| key_error(error.to_string()))?;
+    let key = match rustls_pemfile::private_key(&mut BufReader::new(key_file)) {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(key_error("no private key in the file".to_string())),
+        Err(error) => return Err(key_error(error.to_string())),
+    };
+
+    let mut server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificate_chain, key)
+        .map_err(|error| certificate_error(error.to_string()))?;
+    server_config.alpn_protocols = alpn_protocols;
+
+    Ok(Arc::new(server_config))
+}
+
+fn parse_socket_addr(host: &str, port: u16) -> Result<SocketAddr, LocalError> {
+    match format!("{}:{}", host, port).parse() {
+        Ok(socket_addr) => Ok(socket_addr),
+        Err(_) => Err(InvalidAddress(host.to_string(), port)),
+    }
+}
+
+fn bind_error(error: io::Error, host: String, port: u16) -> LocalError {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => PermissionDenied(host, port),
+        _ => Unknown(host, port),
+    }
+}
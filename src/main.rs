@@ -1,15 +1,27 @@
-use crate::cli::Args;
-use crate::local::UdpListener;
-use crate::upstream::HttpsClient;
+use crate::bootstrap::{read_resolv_conf, BootstrapClient};
+use crate::cache::Cache;
+use crate::cli::{Args, UpstreamMethod, UpstreamStrategy};
+use crate::local::{HttpsListener, TcpListener, TlsListener, UdpListener};
+use crate::resolver::{CacheLayer, LogLayer, Resolver};
+use crate::strategy::{Failover, Fastest, Race, Random, RoundRobin, Strategy};
+use crate::upstream::{ClientConfig, HttpsClient};
 use clap::Parser;
-use std::process::ExitCode;
+use reqwest::Method;
+use std::{process::ExitCode, sync::Arc, time::Duration};
 use tracing::error;
 
 mod bootstrap;
 mod cache;
 mod cli;
 mod error;
+#[cfg(feature = "http3")]
+mod http3;
 mod local;
+#[cfg(feature = "quic")]
+mod quic;
+mod resolver;
+mod strategy;
+mod tls;
 mod upstream;
 mod utils;
 
@@ -18,13 +30,87 @@ async fn main() -> ExitCode {
     tracing_subscriber::fmt().with_target(false).init();
 
     let Args {
-        upstream_address,
+        upstream,
         local_address,
         local_port,
-        upstream_port,
+        tcp_idle_timeout,
+        local_https_port,
+        local_tls_port,
+        tls_certificate,
+        tls_key,
+        upstream_timeout,
+        upstream_pool_idle_timeout,
+        upstream_keepalive_interval,
+        upstream_warm_up,
+        #[cfg(feature = "http3")]
+        upstream_http3,
+        upstream_strategy,
+        upstream_weight,
+        upstream_method,
+        upstream_race_count,
+        mut bootstrap,
+        bootstrap_resolv_conf,
+        cache_size,
+        disable_cache,
+        cache_max_negative_ttl,
+        cache_stale_ttl,
+        cache_prefetch_hits,
+        cache_prefetch_ratio,
     } = cli::Args::parse();
 
-    let https_client = match HttpsClient::new(upstream_address, upstream_port).await {
+    let strategy: Arc<dyn Strategy> = match upstream_strategy {
+        UpstreamStrategy::Failover => Arc::new(Failover),
+        UpstreamStrategy::RoundRobin => Arc::new(RoundRobin::default()),
+        UpstreamStrategy::Fastest => Arc::new(Fastest),
+        UpstreamStrategy::Random => Arc::new(Random),
+        UpstreamStrategy::Race => Arc::new(Race::new(upstream_race_count)),
+    };
+
+    let upstream_method = upstream_method
+        .into_iter()
+        .map(|upstream_method| match upstream_method {
+            UpstreamMethod::Get => Method::GET,
+            UpstreamMethod::Post => Method::POST,
+        })
+        .collect();
+
+    if bootstrap_resolv_conf {
+        bootstrap.extend(read_resolv_conf());
+    }
+    let bootstrap_client = match BootstrapClient::new(bootstrap) {
+        Ok(bootstrap_client) => bootstrap_client,
+        Err(error) => {
+            error!("{}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // the zero values disable the pool idle timeout and the keepalive pings
+    let client_config = ClientConfig {
+        timeout: Duration::from_secs(upstream_timeout),
+        pool_idle_timeout: match upstream_pool_idle_timeout {
+            0 => None,
+            pool_idle_timeout => Some(Duration::from_secs(pool_idle_timeout)),
+        },
+        keepalive_interval: match upstream_keepalive_interval {
+            0 => None,
+            keepalive_interval => Some(Duration::from_secs(keepalive_interval)),
+        },
+        warm_up: upstream_warm_up,
+        #[cfg(feature = "http3")]
+        http3: upstream_http3,
+    };
+
+    let https_client = match HttpsClient::new(
+        upstream,
+        upstream_weight,
+        upstream_method,
+        client_config,
+        bootstrap_client,
+        strategy,
+    )
+    .await
+    {
         Ok(https_client) => https_client,
         Err(error) => {
             error!("{}", error);
@@ -32,13 +118,103 @@ async fn main() -> ExitCode {
         }
     };
 
-    let udp_listener = match UdpListener::new(local_address, local_port, https_client).await {
-        Ok(udp_listener) => udp_listener,
+    // the queries are logged before the cache so that the cached responses are logged too
+    let mut resolver: Arc<dyn Resolver> = Arc::new(https_client);
+    if !disable_cache {
+        let cache = Cache::new(
+            cache_size,
+            Duration::from_secs(cache_max_negative_ttl),
+            Duration::from_secs(cache_stale_ttl),
+            cache_prefetch_hits,
+            cache_prefetch_ratio,
+        );
+        resolver = Arc::new(CacheLayer::new(resolver, cache));
+    }
+    let resolver: Arc<dyn Resolver> = Arc::new(LogLayer::new(resolver));
+
+    let udp_listener =
+        match UdpListener::new(local_address.clone(), local_port, resolver.clone()).await {
+            Ok(udp_listener) => udp_listener,
+            Err(error) => {
+                error!("{}", error);
+                return ExitCode::FAILURE;
+            }
+        };
+
+    let tcp_listener = match TcpListener::new(
+        local_address.clone(),
+        local_port,
+        resolver.clone(),
+        Duration::from_secs(tcp_idle_timeout),
+    )
+    .await
+    {
+        Ok(tcp_listener) => tcp_listener,
         Err(error) => {
             error!("{}", error);
             return ExitCode::FAILURE;
         }
     };
-    udp_listener.listen().await;
+
+    let https_listener = match (local_https_port, &tls_certificate, &tls_key) {
+        (Some(local_https_port), Some(tls_certificate), Some(tls_key)) => {
+            let https_listener = HttpsListener::new(
+                local_address.clone(),
+                local_https_port,
+                resolver.clone(),
+                Duration::from_secs(tcp_idle_timeout),
+                tls_certificate,
+                tls_key,
+            )
+            .await;
+
+            match https_listener {
+                Ok(https_listener) => Some(https_listener),
+                Err(error) => {
+                    error!("{}", error);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let tls_listener = match (local_tls_port, &tls_certificate, &tls_key) {
+        (Some(local_tls_port), Some(tls_certificate), Some(tls_key)) => {
+            let tls_listener = TlsListener::new(
+                local_address,
+                local_tls_port,
+                resolver,
+                Duration::from_secs(tcp_idle_timeout),
+                tls_certificate,
+                tls_key,
+            )
+            .await;
+
+            match tls_listener {
+                Ok(tls_listener) => Some(tls_listener),
+                Err(error) => {
+                    error!("{}", error);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    tokio::join!(
+        udp_listener.listen(),
+        tcp_listener.listen(),
+        async {
+            if let Some(https_listener) = &https_listener {
+                https_listener.listen().await;
+            }
+        },
+        async {
+            if let Some(tls_listener) = &tls_listener {
+                tls_listener.listen().await;
+            }
+        },
+    );
     ExitCode::SUCCESS
 }
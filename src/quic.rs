@@ -0,0 +1,314 @@
+use crate::error::UpstreamError::{self, Build, Malformed, Quic, Serialize, Timeout};
+use crate::tls::build_tls_config;
+use crate::upstream::{build_socket_addr_list, connect_socket_addr_list, ClientConfig, Transport};
+use crate::utils::query_name;
+use async_trait::async_trait;
+use quinn::{
+    crypto::rustls::QuicClientConfig, Connection, Endpoint, IdleTimeout, TransportConfig, VarInt,
+};
+use reqwest::Url;
+use std::{
+    error::Error,
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::timeout};
+use tokio_rustls::rustls;
+use trust_dns_proto::op::message::Message;
+
+pub const DOQ_PORT: u16 = 853;
+const DOQ_ALPN: &[u8] = b"doq";
+const DOQ_NO_ERROR: u32 = 0;
+const LENGTH_PREFIX_SIZE: usize = 2;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+pub struct QuicClient {
+    server_name: String,
+    socket_addr_list: Vec<SocketAddr>,
+    quic_config: quinn::ClientConfig,
+    timeout: Duration,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl fmt::Debug for QuicClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicClient")
+            .field("server_name", &self.server_name)
+            .field("socket_addr_list", &self.socket_addr_list)
+            .finish_non_exhaustive()
+    }
+}
+
+impl QuicClient {
+    pub fn new(
+        url: &Url,
+        socket_addr_list: &[SocketAddr],
+        client_config: &ClientConfig,
+    ) -> Result<Self, UpstreamError> {
+        let (server_name, socket_addr_list) =
+            build_socket_addr_list(url, socket_addr_list, DOQ_PORT);
+
+        Ok(QuicClient {
+            server_name,
+            socket_addr_list,
+            quic_config: build_quic_config(DOQ_ALPN, client_config)?,
+            timeout: client_config.timeout,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn get_connection(&self) -> Result<Connection, BoxError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(quic_connection) = connection.as_ref() {
+            if quic_connection.close_reason().is_none() {
+                return Ok(quic_connection.clone());
+            }
+        }
+
+        let quic_connection = connect(
+            &self.quic_config,
+            &self.server_name,
+            &self.socket_addr_list,
+            self.timeout,
+        )
+        .await?;
+        *connection = Some(quic_connection.clone());
+        Ok(quic_connection)
+    }
+}
+
+#[async_trait]
+impl Transport for QuicClient {
+    async fn send(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        let name = query_name(request_message);
+
+        // RFC 9250 requires the message ID 0 since the streams already tell
+        // the responses apart
+        let mut raw_request_message = request_message.clone();
+        raw_request_message.set_id(0);
+        let raw_request_message = match raw_request_message.to_vec() {
+            Ok(raw_request_message) => raw_request_message,
+            Err(error) => return Err(Serialize(name, error)),
+        };
+
+        let connection = match timeout(self.timeout, self.get_connection()).await {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(error)) => return Err(Quic(name, error)),
+            Err(elapsed) => return Err(Timeout(name, elapsed.into())),
+        };
+
+        let received = connection.stats().udp_rx.datagrams;
+        let raw_response_message = match timeout(
+            self.timeout,
+            send_message(&connection, &raw_request_message),
+        )
+        .await
+        {
+            Ok(Ok(raw_response_message)) => raw_response_message,
+            Ok(Err(error)) => return Err(Quic(name, error)),
+            Err(elapsed) => {
+                // the other queries are on their own streams of the connection, so
+                // it's only closed as broken when nothing arrived during this query
+                if connection.stats().udp_rx.datagrams == received {
+                    connection.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+                }
+                return Err(Timeout(name, elapsed.into()));
+            }
+        };
+
+        let mut response_message = match Message::from_vec(&raw_response_message) {
+            Ok(response_message) => response_message,
+            Err(error) => return Err(Malformed(name, error)),
+        };
+        response_message.set_id(request_message.id());
+        Ok(response_message)
+    }
+}
+
+async fn send_message(
+    connection: &Connection,
+    raw_request_message: &[u8],
+) -> Result<Vec<u8>, BoxError> {
+    let request_length = u16::try_from(raw_request_message.len())?;
+    let mut request = request_length.to_be_bytes().to_vec();
+    request.extend_from_slice(raw_request_message);
+
+    // each query is sent on its own stream, and the end of the stream marks
+    // the end of the query
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    send_stream.write_all(&request).await?;
+    send_stream.finish()?;
+
+    let response = recv_stream
+        .read_to_end(LENGTH_PREFIX_SIZE + usize::from(u16::MAX))
+        .await?;
+    if response.len() < LENGTH_PREFIX_SIZE {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let response_length = usize::from(u16::from_be_bytes([response[0], response[1]]));
+    if response.len() - LENGTH_PREFIX_SIZE != response_length {
+        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+    }
+    Ok(response[LENGTH_PREFIX_SIZE..].to_vec())
+}
+
+pub fn build_quic_config(
+    alpn: &[u8],
+    client_config: &ClientConfig,
+) -> Result<quinn::ClientConfig, UpstreamError> {
+    let tls_config = build_tls_config(&[&rustls::version::TLS13], alpn)?;
+
+    let quic_tls_config = match QuicClientConfig::try_from(tls_config) {
+        Ok(quic_tls_config) => quic_tls_config,
+        Err(_) => return Err(Build),
+    };
+
+    let mut transport_config = TransportConfig::default();
+    transport_config.keep_alive_interval(client_config.keepalive_interval);
+    transport_config.max_idle_timeout(
+        client_config
+            .pool_idle_timeout
+            .and_then(|pool_idle_timeout| IdleTimeout::try_from(pool_idle_timeout).ok()),
+    );
+
+    let mut quic_config = quinn::ClientConfig::new(Arc::new(quic_tls_config));
+    quic_config.transport_config(Arc::new(transport_config));
+    Ok(quic_config)
+}
+
+pub async fn connect(
+    quic_config: &quinn::ClientConfig,
+    server_name: &str,
+    socket_addr_list: &[SocketAddr],
+    connect_timeout: Duration,
+) -> Result<Connection, BoxError> {
+    connect_socket_addr_list(socket_addr_list, connect_timeout, "QUIC", |socket_addr| {
+        connect_addr(quic_config, server_name, socket_addr)
+    })
+    .await
+}
+
+async fn connect_addr(
+    quic_config: &quinn::ClientConfig,
+    server_name: &str,
+    socket_addr: SocketAddr,
+) -> Result<Connection, BoxError> {
+    let local_addr = match socket_addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let endpoint = Endpoint::client(local_addr)?;
+    let connection = endpoint
+        .connect_with(quic_config.clone(), socket_addr, server_name)?
+        .await?;
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuicClient, DOQ_ALPN, LENGTH_PREFIX_SIZE};
+    use crate::upstream::Transport;
+    use crate::utils::build_request_message;
+    use futures::join;
+    use quinn::{
+        crypto::rustls::{QuicClientConfig, QuicServerConfig},
+        Endpoint,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::Mutex;
+    use tokio_rustls::rustls::{
+        pki_types::PrivatePkcs8KeyDer, version::TLS13, ClientConfig as RustlsClientConfig,
+        RootCertStore, ServerConfig,
+    };
+    use trust_dns_proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::RecordType,
+    };
+
+    #[tokio::test]
+    async fn test_quic_client_streams() {
+        let certified_key =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let certificate = certified_key.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der());
+        let mut server_config = ServerConfig::builder_with_protocol_versions(&[&TLS13])
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], key.into())
+            .unwrap();
+        server_config.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(
+            QuicServerConfig::try_from(server_config).unwrap(),
+        ));
+
+        let endpoint = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let socket_addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+
+            // both queries are read before the responses are sent in the reverse order,
+            // and a query without the length prefix or with a non-zero ID gets FORMERR
+            let mut stream_list = Vec::new();
+            for _ in 0..2 {
+                let (send_stream, mut recv_stream) = connection.accept_bi().await.unwrap();
+                let request = recv_stream
+                    .read_to_end(usize::from(u16::MAX))
+                    .await
+                    .unwrap();
+                let length = usize::from(u16::from_be_bytes([request[0], request[1]]));
+                let mut response_message =
+                    Message::from_vec(&request[LENGTH_PREFIX_SIZE..]).unwrap();
+                if length != request.len() - LENGTH_PREFIX_SIZE || response_message.id() != 0 {
+                    response_message.set_response_code(ResponseCode::FormErr);
+                }
+                response_message.set_message_type(MessageType::Response);
+                stream_list.push((send_stream, response_message.to_vec().unwrap()));
+            }
+            for (mut send_stream, raw_response_message) in stream_list.into_iter().rev() {
+                let length = u16::try_from(raw_response_message.len()).unwrap();
+                send_stream.write_all(&length.to_be_bytes()).await.unwrap();
+                send_stream.write_all(&raw_response_message).await.unwrap();
+                send_stream.finish().unwrap();
+            }
+            connection.closed().await;
+        });
+
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add(certificate).unwrap();
+        let mut tls_config = RustlsClientConfig::builder_with_protocol_versions(&[&TLS13])
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        let quic_client = QuicClient {
+            server_name: String::from("localhost"),
+            socket_addr_list: vec![socket_addr],
+            quic_config: quinn::ClientConfig::new(Arc::new(
+                QuicClientConfig::try_from(tls_config).unwrap(),
+            )),
+            timeout: Duration::from_secs(10),
+            connection: Mutex::new(None),
+        };
+
+        let mut first_request_message =
+            build_request_message("example.com".parse().unwrap(), RecordType::A);
+        first_request_message.set_id(1);
+        let mut second_request_message =
+            build_request_message("example.org".parse().unwrap(), RecordType::A);
+        second_request_message.set_id(2);
+        let (first_response_message, second_response_message) = join!(
+            quic_client.send(&first_request_message),
+            quic_client.send(&second_request_message)
+        );
+
+        for (request_message, response_message) in [
+            (first_request_message, first_response_message.unwrap()),
+            (second_request_message, second_response_message.unwrap()),
+        ] {
+            assert_eq!(response_message.id(), request_message.id());
+            assert_eq!(response_message.queries(), request_message.queries());
+            assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        }
+    }
+}
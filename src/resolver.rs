@@ -0,0 +1,288 @@
+use crate::cache::Cache;
+use crate::error::UpstreamError;
+use async_trait::async_trait;
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time::timeout};
+use tracing::{debug, info, warn};
+use trust_dns_proto::op::message::Message;
+
+#[async_trait]
+pub trait Resolver: Debug + Send + Sync {
+    async fn resolve(&self, request_message: &Message) -> Result<Message, UpstreamError>;
+
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+// RFC 8767 suggests 1.8 seconds as the client response timer, which answers
+// before the stub resolvers give up on the query
+const STALE_ANSWER_TIMEOUT: Duration = Duration::from_millis(1800);
+
+#[derive(Debug)]
+pub struct CacheLayer {
+    resolver: Arc<dyn Resolver>,
+    cache: Cache,
+}
+
+impl CacheLayer {
+    pub fn new(resolver: Arc<dyn Resolver>, cache: Cache) -> Self {
+        CacheLayer { resolver, cache }
+    }
+
+    fn refresh(&self, request_message: Message) -> JoinHandle<Result<Message, UpstreamError>> {
+        let resolver = self.resolver.clone();
+        let mut cache = self.cache.clone();
+        tokio::spawn(async move {
+            let result = resolver.resolve(&request_message).await;
+            if let Ok(response_message) = &result {
+                cache.put(response_message.clone());
+            }
+            // the failed or uncacheable responses leave the old entry in place, so
+            // its flag is cleared to let the next hit refresh it again
+            cache.prefetch_done(&request_message);
+            result
+        })
+    }
+}
+
+#[async_trait]
+impl Resolver for CacheLayer {
+    async fn resolve(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        // the cache is shared between its clones, so the clone is only needed
+        // for the mutable methods
+        let mut cache = self.cache.clone();
+        if let Some(response_message) = cache.get(request_message) {
+            if cache.prefetch(request_message) {
+                self.refresh(request_message.clone());
+            }
+            return Ok(response_message);
+        }
+
+        let stale_message = match cache.get_stale(request_message) {
+            Some(stale_message) => stale_message,
+            None => {
+                let response_message = self.resolver.resolve(request_message).await?;
+                cache.put(response_message.clone());
+                return Ok(response_message);
+            }
+        };
+
+        if !self.resolver.is_available() {
+            debug!("the upstreams are unavailable, serving the stale response");
+            return Ok(stale_message);
+        }
+        if !cache.start_refresh(request_message) {
+            debug!("the response is being refreshed, serving the stale response");
+            return Ok(stale_message);
+        }
+
+        // the refresh keeps running in the background after the timer fires,
+        // so its response still reaches the cache
+        match timeout(STALE_ANSWER_TIMEOUT, self.refresh(request_message.clone())).await {
+            Ok(Ok(Ok(response_message))) => Ok(response_message),
+            Ok(Ok(Err(error))) => {
+                warn!("{}, serving the stale response", error);
+                Ok(stale_message)
+            }
+            Ok(Err(_)) => Ok(stale_message),
+            Err(_) => {
+                warn!("the upstream is too slow, serving the stale response");
+                Ok(stale_message)
+            }
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.resolver.is_available()
+    }
+}
+
+#[derive(Debug)]
+pub struct LogLayer {
+    resolver: Arc<dyn Resolver>,
+}
+
+impl LogLayer {
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        LogLayer { resolver }
+    }
+}
+
+#[async_trait]
+impl Resolver for LogLayer {
+    async fn resolve(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        for request_record in request_message.queries().iter() {
+            info!(
+                phase = "request",
+                "{} {} {}",
+                request_record.name(),
+                request_record.query_class(),
+                request_record.query_type(),
+            );
+        }
+
+        let response_message = self.resolver.resolve(request_message).await?;
+        for response_record in response_message.answers().iter() {
+            info!(phase = "response", "{}", response_record);
+        }
+        Ok(response_message)
+    }
+
+    fn is_available(&self) -> bool {
+        self.resolver.is_available()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheLayer, Resolver};
+    use crate::cache::Cache;
+    use crate::error::UpstreamError::{self, Resolve};
+    use crate::utils::{build_request_message, query_name};
+    use async_trait::async_trait;
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::time::sleep;
+    use trust_dns_proto::{
+        op::{Message, MessageType},
+        rr::{Name, RData, Record, RecordType},
+    };
+
+    #[derive(Debug, Default)]
+    struct MockResolver {
+        count: AtomicUsize,
+        is_down: AtomicBool,
+        is_slow: AtomicBool,
+        is_unavailable: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Resolver for MockResolver {
+        async fn resolve(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            if self.is_slow.load(Ordering::Relaxed) {
+                sleep(Duration::from_secs(5)).await;
+            }
+            if self.is_down.load(Ordering::Relaxed) {
+                return Err(Resolve(query_name(request_message)));
+            }
+
+            let mut response_message = request_message.clone();
+            response_message.set_message_type(MessageType::Response);
+            let name = request_message.queries()[0].name().clone();
+            let mut answer = Record::with(name, RecordType::A, 0);
+            answer.set_data(Some(RData::A(Ipv4Addr::new(10, 0, 0, 1))));
+            response_message.add_answer(answer);
+            Ok(response_message)
+        }
+
+        fn is_available(&self) -> bool {
+            !self.is_unavailable.load(Ordering::Relaxed)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_layer() {
+        let mock_resolver = Arc::new(MockResolver::default());
+        let mut cache = Cache::default();
+        let cache_layer = CacheLayer::new(mock_resolver.clone(), cache.clone());
+
+        let request_name: Name = "example.com".parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        let mut response_message = request_message.clone();
+        response_message.set_message_type(MessageType::Response);
+        let mut answer = Record::with("example.com".parse().unwrap(), RecordType::A, 300);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(10, 0, 0, 2))));
+        response_message.add_answer(answer);
+        cache.put(response_message);
+
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(message.id(), request_message.id());
+        assert_eq!(
+            message.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(10, 0, 0, 2)))
+        );
+        assert_eq!(mock_resolver.count.load(Ordering::Relaxed), 0);
+
+        let request_name: Name = "example.org".parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(
+            message.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+        assert_eq!(mock_resolver.count.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_layer_stale() {
+        let mock_resolver = Arc::new(MockResolver::default());
+        let cache = Cache::new(
+            1024,
+            Duration::from_secs(3600),
+            Duration::from_secs(60),
+            0,
+            0.9,
+        );
+        let cache_layer = CacheLayer::new(mock_resolver.clone(), cache);
+
+        // the mock responds with the TTL 0, so the response is stale right away
+        let request_name: Name = "example.com".parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        cache_layer.resolve(&request_message).await.unwrap();
+
+        mock_resolver.is_down.store(true, Ordering::Relaxed);
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(message.id(), request_message.id());
+        assert_eq!(mock_resolver.count.load(Ordering::Relaxed), 2);
+
+        let request_name: Name = "example.org".parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        assert!(cache_layer.resolve(&request_message).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cache_layer_stale_timeout() {
+        let mock_resolver = Arc::new(MockResolver::default());
+        let cache = Cache::new(
+            1024,
+            Duration::from_secs(3600),
+            Duration::from_secs(60),
+            0,
+            0.9,
+        );
+        let cache_layer = CacheLayer::new(mock_resolver.clone(), cache);
+
+        let request_name: Name = "example.com".parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        cache_layer.resolve(&request_message).await.unwrap();
+
+        // the slow upstream is answered with the stale response, and the second
+        // query doesn't start another refresh while the first one is in flight
+        mock_resolver.is_slow.store(true, Ordering::Relaxed);
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(message.answers()[0].ttl(), 30);
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(message.answers()[0].ttl(), 30);
+        assert_eq!(mock_resolver.count.load(Ordering::Relaxed), 2);
+
+        // the refresh finishes in the background, so the next query starts a new one
+        sleep(Duration::from_secs(5)).await;
+        mock_resolver.is_slow.store(false, Ordering::Relaxed);
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(message.answers()[0].ttl(), 0);
+        assert_eq!(mock_resolver.count.load(Ordering::Relaxed), 3);
+
+        mock_resolver.is_unavailable.store(true, Ordering::Relaxed);
+        let message = cache_layer.resolve(&request_message).await.unwrap();
+        assert_eq!(message.answers()[0].ttl(), 30);
+        assert_eq!(mock_resolver.count.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::error::UpstreamError::{self, Resolve};
+use crate::upstream::Upstream;
+use crate::utils::query_name;
+use async_trait::async_trait;
+use futures::future::select_ok;
+use rand::{seq::SliceRandom, thread_rng};
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use trust_dns_proto::op::message::Message;
+
+#[async_trait]
+pub trait Strategy: Debug + Send + Sync {
+    fn select<'a>(&self, upstream_list: &'a [Upstream]) -> Vec<&'a Upstream>;
+
+    async fn resolve(
+        &self,
+        upstream_list: &[Upstream],
+        request_message: &Message,
+    ) -> Result<Message, UpstreamError> {
+        resolve_in_order(self.select(upstream_list), request_message).await
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Failover;
+
+impl Strategy for Failover {
+    fn select<'a>(&self, upstream_list: &'a [Upstream]) -> Vec<&'a Upstream> {
+        upstream_list.iter().collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Strategy for RoundRobin {
+    fn select<'a>(&self, upstream_list: &'a [Upstream]) -> Vec<&'a Upstream> {
+        let mut selected_list: Vec<_> = upstream_list.iter().collect();
+        if !selected_list.is_empty() {
+            let next = self.next.fetch_add(1, Ordering::Relaxed);
+            selected_list.rotate_left(next % upstream_list.len());
+        }
+        selected_list
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Fastest;
+
+impl Strategy for Fastest {
+    fn select<'a>(&self, upstream_list: &'a [Upstream]) -> Vec<&'a Upstream> {
+        // the upstreams without any RTT sample are tried first so that they get measured
+        let mut selected_list: Vec<_> = upstream_list.iter().collect();
+        selected_list.sort_by_key(|upstream| upstream.rtt());
+        selected_list
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl Strategy for Random {
+    fn select<'a>(&self, upstream_list: &'a [Upstream]) -> Vec<&'a Upstream> {
+        let mut rng = thread_rng();
+        match upstream_list
+            .choose_multiple_weighted(&mut rng, upstream_list.len(), |upstream| upstream.weight())
+        {
+            Ok(selected_list) => selected_list.collect(),
+            Err(_) => upstream_list.iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Race {
+    count: usize,
+}
+
+impl Race {
+    pub fn new(count: usize) -> Self {
+        Race {
+            count: count.max(1),
+        }
+    }
+}
+
+#[async_trait]
+impl Strategy for Race {
+    fn select<'a>(&self, upstream_list: &'a [Upstream]) -> Vec<&'a Upstream> {
+        upstream_list.iter().collect()
+    }
+
+    async fn resolve(
+        &self,
+        upstream_list: &[Upstream],
+        request_message: &Message,
+    ) -> Result<Message, UpstreamError> {
+        let mut selected_list = filter_by_health(self.select(upstream_list));
+        if selected_list.is_empty() {
+            return Err(Resolve(query_name(request_message)));
+        }
+        let remaining_list = selected_list.split_off(self.count.min(selected_list.len()));
+
+        let race_list = selected_list
+            .into_iter()
+            .map(|upstream| Box::pin(upstream.resolve(request_message)));
+        match select_ok(race_list).await {
+            Ok((response_message, _)) => Ok(response_message),
+            Err(error) if remaining_list.is_empty() => Err(error),
+            Err(_) => resolve_in_order(remaining_list, request_message).await,
+        }
+    }
+}
+
+// the unhealthy upstreams are skipped until their backoff expires, but they
+// are still tried as a last resort if every upstream is unhealthy, except for
+// the rate limited ones that are never queried before their Retry-After passes
+fn filter_by_health(upstream_list: Vec<&Upstream>) -> Vec<&Upstream> {
+    let upstream_list: Vec<_> = upstream_list
+        .into_iter()
+        .filter(|upstream| !upstream.is_rate_limited())
+        .collect();
+    if !upstream_list.iter().any(|upstream| upstream.is_healthy()) {
+        return upstream_list;
+    }
+    upstream_list
+        .into_iter()
+        .filter(|upstream| upstream.is_healthy())
+        .collect()
+}
+
+async fn resolve_in_order(
+    upstream_list: Vec<&Upstream>,
+    request_message: &Message,
+) -> Result<Message, UpstreamError> {
+    let mut last_error = Resolve(query_name(request_message));
+    for upstream in filter_by_health(upstream_list) {
+        match upstream.resolve(request_message).await {
+            Ok(response_message) => return Ok(response_message),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{filter_by_health, Failover, Fastest, Race, Random, RoundRobin, Strategy};
+    use crate::bootstrap::BootstrapClient;
+    use crate::error::UpstreamError::Resolve;
+    use crate::upstream::{ClientConfig, Upstream};
+    use crate::utils::build_request_message;
+    use reqwest::Method;
+    use std::{ptr, time::Duration};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    async fn build_upstream_list() -> Vec<Upstream> {
+        build_weighted_upstream_list(
+            &[
+                "https://1.1.1.1/dns-query",
+                "https://8.8.8.8/dns-query",
+                "https://9.9.9.9/dns-query",
+            ],
+            &[1, 1, 1],
+        )
+        .await
+    }
+
+    async fn build_weighted_upstream_list(url_list: &[&str], weight_list: &[u32]) -> Vec<Upstream> {
+        let bootstrap_client = BootstrapClient::new(Vec::new()).unwrap();
+        let mut upstream_list = Vec::new();
+        for (url, weight) in url_list.iter().zip(weight_list) {
+            let upstream = Upstream::new(
+                url.parse().unwrap(),
+                *weight,
+                Method::POST,
+                &ClientConfig::default(),
+                &bootstrap_client,
+            )
+            .await
+            .unwrap();
+            upstream_list.push(upstream);
+        }
+        upstream_list
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let upstream_list = build_upstream_list().await;
+        let selected_list = Failover.select(&upstream_list);
+        for (upstream, selected_upstream) in upstream_list.iter().zip(selected_list) {
+            assert!(ptr::eq(upstream, selected_upstream));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let upstream_list = build_upstream_list().await;
+        let round_robin = RoundRobin::default();
+        for index in 0..6 {
+            let selected_list = round_robin.select(&upstream_list);
+            assert_eq!(selected_list.len(), upstream_list.len());
+            assert!(ptr::eq(selected_list[0], &upstream_list[index % 3]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_health() {
+        let upstream_list = build_upstream_list().await;
+        upstream_list[0].record_failure(None);
+        let selected_list = filter_by_health(Failover.select(&upstream_list));
+        assert_eq!(selected_list.len(), 2);
+        assert!(ptr::eq(selected_list[0], &upstream_list[1]));
+
+        upstream_list[1].record_failure(None);
+        upstream_list[2].record_failure(None);
+        let selected_list = filter_by_health(Failover.select(&upstream_list));
+        assert_eq!(selected_list.len(), 3);
+
+        upstream_list[0].record_failure(Some(Duration::from_secs(120)));
+        let selected_list = filter_by_health(Failover.select(&upstream_list));
+        assert_eq!(selected_list.len(), 2);
+        assert!(ptr::eq(selected_list[0], &upstream_list[1]));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let upstream_list = build_upstream_list().await;
+        for upstream in upstream_list.iter() {
+            upstream.record_failure(Some(Duration::from_secs(120)));
+        }
+
+        let request_message = build_request_message(Name::root(), RecordType::NS);
+        let result = Failover.resolve(&upstream_list, &request_message).await;
+        assert!(matches!(result, Err(Resolve(_))));
+        let result = Race::new(2).resolve(&upstream_list, &request_message).await;
+        assert!(matches!(result, Err(Resolve(_))));
+    }
+
+    #[tokio::test]
+    async fn test_fastest() {
+        let upstream_list = build_upstream_list().await;
+        upstream_list[0].record_success(Duration::from_millis(50));
+        upstream_list[1].record_success(Duration::from_millis(10));
+        let selected_list = Fastest.select(&upstream_list);
+        assert!(ptr::eq(selected_list[0], &upstream_list[2]));
+        assert!(ptr::eq(selected_list[1], &upstream_list[1]));
+        assert!(ptr::eq(selected_list[2], &upstream_list[0]));
+    }
+
+    #[tokio::test]
+    async fn test_random() {
+        let upstream_list = build_weighted_upstream_list(
+            &[
+                "https://1.1.1.1/dns-query",
+                "https://8.8.8.8/dns-query",
+                "https://9.9.9.9/dns-query",
+            ],
+            &[0, 1, 1],
+        )
+        .await;
+        for _ in 0..100 {
+            let selected_list = Random.select(&upstream_list);
+            assert_eq!(selected_list.len(), upstream_list.len());
+            assert!(ptr::eq(selected_list[2], &upstream_list[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        // nothing listens on these ports, so every upstream fails right away
+        let upstream_list = build_weighted_upstream_list(
+            &[
+                "https://127.0.0.1:1/dns-query",
+                "https://127.0.0.1:2/dns-query",
+                "https://127.0.0.1:3/dns-query",
+            ],
+            &[1, 1, 1],
+        )
+        .await;
+        let request_message = build_request_message(Name::root(), RecordType::NS);
+
+        for count in [2, 3] {
+            let error = Race::new(count)
+                .resolve(&upstream_list, &request_message)
+                .await
+                .unwrap_err();
+            assert!(!matches!(error, Resolve(_)));
+        }
+    }
+}
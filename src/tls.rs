@@ -0,0 +1,547 @@
+use crate::error::UpstreamError::{self, Build, InvalidUrl, Malformed, Serialize, Timeout, Tls};
+use crate::upstream::{build_socket_addr_list, connect_socket_addr_list, ClientConfig, Transport};
+use crate::utils::query_name;
+use async_trait::async_trait;
+use reqwest::Url;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{self, pki_types::ServerName, RootCertStore, SupportedProtocolVersion},
+    TlsConnector,
+};
+use tracing::debug;
+use trust_dns_proto::op::message::Message;
+
+pub const DOT_PORT: u16 = 853;
+const DOT_ALPN: &[u8] = b"dot";
+
+type PendingMap = Arc<Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>>;
+
+pub struct TlsClient {
+    server_name: ServerName<'static>,
+    socket_addr_list: Vec<SocketAddr>,
+    connector: TlsConnector,
+    timeout: Duration,
+    idle_timeout: Option<Duration>,
+    connection: tokio::sync::Mutex<Option<Arc<TlsConnection>>>,
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("server_name", &self.server_name)
+            .field("socket_addr_list", &self.socket_addr_list)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsClient {
+    pub fn new(
+        url: &Url,
+        socket_addr_list: &[SocketAddr],
+        client_config: &ClientConfig,
+    ) -> Result<Self, UpstreamError> {
+        let (server_name, socket_addr_list) =
+            build_socket_addr_list(url, socket_addr_list, DOT_PORT);
+        let server_name = match ServerName::try_from(server_name) {
+            Ok(server_name) => server_name,
+            Err(_) => return Err(InvalidUrl(url.to_string())),
+        };
+
+        let tls_config = build_tls_config(rustls::DEFAULT_VERSIONS, DOT_ALPN)?;
+        Ok(TlsClient {
+            server_name,
+            socket_addr_list,
+            connector: TlsConnector::from(Arc::new(tls_config)),
+            timeout: client_config.timeout,
+            idle_timeout: client_config.pool_idle_timeout,
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    async fn get_connection(&self) -> Result<(Arc<TlsConnection>, bool), io::Error> {
+        let mut connection = self.connection.lock().await;
+        if let Some(tls_connection) = connection.as_ref() {
+            if !tls_connection.is_closed() {
+                return Ok((tls_connection.clone(), true));
+            }
+        }
+
+        let tls_connection = Arc::new(self.connect().await?);
+        *connection = Some(tls_connection.clone());
+        Ok((tls_connection, false))
+    }
+
+    async fn connect(&self) -> Result<TlsConnection, io::Error> {
+        connect_socket_addr_list(&self.socket_addr_list, self.timeout, "TLS", |socket_addr| {
+            self.connect_addr(socket_addr)
+        })
+        .await
+    }
+
+    async fn connect_addr(&self, socket_addr: SocketAddr) -> Result<TlsConnection, io::Error> {
+        let tcp_stream = TcpStream::connect(socket_addr).await?;
+        tcp_stream.set_nodelay(true)?;
+        let tls_stream = self
+            .connector
+            .connect(self.server_name.clone(), tcp_stream)
+            .await?;
+        Ok(TlsConnection::new(
+            tls_stream,
+            self.timeout,
+            self.idle_timeout,
+        ))
+    }
+}
+
+#[async_trait]
+impl Transport for TlsClient {
+    async fn send(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        let name = query_name(request_message);
+        let raw_request_message = match request_message.to_vec() {
+            Ok(raw_request_message) => raw_request_message,
+            Err(error) => return Err(Serialize(name, error)),
+        };
+
+        let mut is_retried = false;
+        let raw_response_message = loop {
+            let (connection, is_reused) = match timeout(self.timeout, self.get_connection()).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(error)) => return Err(Tls(name, error)),
+                Err(elapsed) => return Err(Timeout(name, elapsed.into())),
+            };
+
+            match timeout(self.timeout, connection.send(&raw_request_message)).await {
+                Ok(Ok(raw_response_message)) => break raw_response_message,
+                // the server might have closed the idle connection before the reader
+                // noticed, so the query is retried once on a new connection
+                Ok(Err(error))
+                    if is_reused && !is_retried && error.kind() != io::ErrorKind::WouldBlock =>
+                {
+                    debug!("the reused TLS connection failed: {}, reconnecting", error);
+                    is_retried = true;
+                }
+                Ok(Err(error)) => return Err(Tls(name, error)),
+                // the connection is left open for the other pipelined queries, since
+                // the reader closes it if the server stops responding
+                Err(elapsed) => return Err(Timeout(name, elapsed.into())),
+            }
+        };
+
+        let mut response_message = match Message::from_vec(&raw_response_message) {
+            Ok(response_message) => response_message,
+            Err(error) => return Err(Malformed(name, error)),
+        };
+        response_message.set_id(request_message.id());
+        Ok(response_message)
+    }
+}
+
+struct TlsConnection {
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pending: PendingMap,
+    next_id: AtomicU16,
+    closed: Arc<AtomicBool>,
+    queued: Arc<Notify>,
+    reader: JoinHandle<()>,
+}
+
+impl TlsConnection {
+    fn new(
+        tls_stream: TlsStream<TcpStream>,
+        response_timeout: Duration,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        let (reader, writer) = split(tls_stream);
+        let pending = PendingMap::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let queued = Arc::new(Notify::new());
+        let reader = tokio::spawn(read_responses(
+            reader,
+            pending.clone(),
+            closed.clone(),
+            queued.clone(),
+            response_timeout,
+            idle_timeout,
+        ));
+
+        TlsConnection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            next_id: AtomicU16::new(0),
+            closed,
+            queued,
+            reader,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.reader.abort();
+        self.pending.lock().unwrap().clear();
+    }
+
+    async fn send(&self, raw_request_message: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let length = match u16::try_from(raw_request_message.len()) {
+            Ok(length) => length,
+            Err(_) => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+
+        // the queries are pipelined on the connection, so each one gets a unique
+        // message ID to match the responses that might arrive out of order
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.len() >= usize::from(u16::MAX) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&id) {
+                id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.insert(id, sender);
+            id
+        };
+        self.queued.notify_one();
+        let _pending_guard = PendingGuard {
+            pending: &self.pending,
+            id,
+        };
+
+        let mut request = Vec::with_capacity(raw_request_message.len() + 2);
+        request.extend_from_slice(&length.to_be_bytes());
+        request.extend_from_slice(&id.to_be_bytes());
+        request.extend_from_slice(&raw_request_message[2..]);
+
+        let mut writer = self.writer.lock().await;
+        if let Err(error) = async {
+            writer.write_all(&request).await?;
+            writer.flush().await
+        }
+        .await
+        {
+            self.close();
+            return Err(error);
+        }
+        drop(writer);
+
+        match receiver.await {
+            Ok(raw_response_message) => Ok(raw_response_message),
+            Err(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl Drop for TlsConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// the pending entry is removed when the query finishes or is cancelled by
+// the timeout, so that its message ID can be reused
+struct PendingGuard<'a> {
+    pending: &'a PendingMap,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn read_responses(
+    reader: ReadHalf<TlsStream<TcpStream>>,
+    pending: PendingMap,
+    closed: Arc<AtomicBool>,
+    queued: Arc<Notify>,
+    response_timeout: Duration,
+    idle_timeout: Option<Duration>,
+) {
+    let mut reader = BufReader::new(reader);
+    loop {
+        // the connection is closed when it stays idle, or when nothing arrives
+        // for the pending queries in time as the connection is probably broken
+        let is_idle = pending.lock().unwrap().is_empty();
+        let wait_timeout = match is_idle {
+            true => idle_timeout,
+            false => Some(response_timeout),
+        };
+
+        // fill_buf is cancel safe, so waiting for the next response can time out
+        // without losing any data
+        let fill_buf = async {
+            let fill_buf = reader.fill_buf();
+            let result = match wait_timeout {
+                Some(wait_timeout) => timeout(wait_timeout, fill_buf).await.ok()?,
+                None => fill_buf.await,
+            };
+            Some(result.map(|buffer| buffer.is_empty()))
+        };
+
+        // a new query wakes up the idle reader to wait with the response timeout
+        // instead, or a server gone silent during the idle period isn't noticed
+        let result = match is_idle {
+            true => tokio::select! {
+                result = fill_buf => result,
+                _ = queued.notified() => continue,
+            },
+            false => fill_buf.await,
+        };
+        match result {
+            Some(Ok(false)) => (),
+            _ => break,
+        }
+
+        let length = match reader.read_u16().await {
+            Ok(length) => length,
+            Err(_) => break,
+        };
+        let mut raw_response_message = vec![0; length.into()];
+        if reader.read_exact(&mut raw_response_message).await.is_err()
+            || raw_response_message.len() < 2
+        {
+            break;
+        }
+
+        let id = u16::from_be_bytes([raw_response_message[0], raw_response_message[1]]);
+        match pending.lock().unwrap().remove(&id) {
+            Some(sender) => {
+                let _ = sender.send(raw_response_message);
+            }
+            None => debug!("received an unexpected response with the ID {}", id),
+        }
+    }
+
+    closed.store(true, Ordering::Relaxed);
+    pending.lock().unwrap().clear();
+}
+
+pub fn build_tls_config(
+    protocol_versions: &[&'static SupportedProtocolVersion],
+    alpn: &[u8],
+) -> Result<rustls::ClientConfig, UpstreamError> {
+    let mut root_store = RootCertStore::empty();
+    let certificate_result = rustls_native_certs::load_native_certs();
+    root_store.add_parsable_certificates(certificate_result.certs);
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls_config = match rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(protocol_versions)
+    {
+        Ok(tls_config) => tls_config
+            .with_root_certificates(root_store)
+            .with_no_client_auth(),
+        Err(_) => return Err(Build),
+    };
+    tls_config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(tls_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TlsClient, DOT_PORT};
+    use crate::error::UpstreamError::{InvalidUrl, Timeout};
+    use crate::upstream::{ClientConfig, Transport};
+    use crate::utils::build_request_message;
+    use futures::join;
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::sleep,
+    };
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{PrivatePkcs8KeyDer, ServerName},
+            ClientConfig as RustlsClientConfig, RootCertStore, ServerConfig,
+        },
+        TlsAcceptor, TlsConnector,
+    };
+    use trust_dns_proto::{
+        op::{Message, MessageType},
+        rr::RecordType,
+    };
+
+    #[test]
+    fn test_tls_client_new() {
+        let tls_client = TlsClient::new(
+            &"tls://1.1.1.1".parse().unwrap(),
+            &[],
+            &ClientConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            tls_client.socket_addr_list,
+            vec![SocketAddr::new([1, 1, 1, 1].into(), DOT_PORT)]
+        );
+
+        let result = TlsClient::new(
+            &"tls://example..com".parse().unwrap(),
+            &[],
+            &ClientConfig::default(),
+        );
+        assert!(matches!(result, Err(InvalidUrl(_))));
+    }
+
+    #[tokio::test]
+    async fn test_tls_client_pipelining() {
+        let certified_key =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let certificate = certified_key.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der());
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], key.into())
+            .unwrap();
+        let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = tcp_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp_stream, _) = tcp_listener.accept().await.unwrap();
+            let mut tls_stream = tls_acceptor.accept(tcp_stream).await.unwrap();
+
+            // both queries are read before the responses are sent in the reverse order
+            let mut raw_response_message_list = Vec::new();
+            for _ in 0..2 {
+                let length = tls_stream.read_u16().await.unwrap();
+                let mut buffer = vec![0; length.into()];
+                tls_stream.read_exact(&mut buffer).await.unwrap();
+                let mut response_message = Message::from_vec(&buffer).unwrap();
+                response_message.set_message_type(MessageType::Response);
+                raw_response_message_list.push(response_message.to_vec().unwrap());
+            }
+            for raw_response_message in raw_response_message_list.iter().rev() {
+                let length = u16::try_from(raw_response_message.len()).unwrap();
+                tls_stream.write_u16(length).await.unwrap();
+                tls_stream.write_all(raw_response_message).await.unwrap();
+            }
+            tls_stream.flush().await.unwrap();
+            let _ = tls_stream.read_u16().await;
+        });
+
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add(certificate).unwrap();
+        let tls_config = RustlsClientConfig::builder()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        let tls_client = TlsClient {
+            server_name: ServerName::try_from("localhost").unwrap(),
+            socket_addr_list: vec![socket_addr],
+            connector: TlsConnector::from(Arc::new(tls_config)),
+            timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            connection: tokio::sync::Mutex::new(None),
+        };
+
+        let first_request_message =
+            build_request_message("example.com".parse().unwrap(), RecordType::A);
+        let second_request_message =
+            build_request_message("example.org".parse().unwrap(), RecordType::A);
+        let (first_response_message, second_response_message) = join!(
+            tls_client.send(&first_request_message),
+            tls_client.send(&second_request_message)
+        );
+
+        for (request_message, response_message) in [
+            (first_request_message, first_response_message.unwrap()),
+            (second_request_message, second_response_message.unwrap()),
+        ] {
+            assert_eq!(response_message.id(), request_message.id());
+            assert_eq!(response_message.queries(), request_message.queries());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_client_silent_after_idle() {
+        let certified_key =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let certificate = certified_key.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der());
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], key.into())
+            .unwrap();
+        let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = tcp_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // the first connection answers only the first query and then stays
+            // open without answering, while the second one answers every query
+            for answered_count in [1, usize::MAX] {
+                let (tcp_stream, _) = tcp_listener.accept().await.unwrap();
+                let mut tls_stream = tls_acceptor.accept(tcp_stream).await.unwrap();
+                tokio::spawn(async move {
+                    let mut query_count = 0;
+                    while let Ok(length) = tls_stream.read_u16().await {
+                        let mut buffer = vec![0; length.into()];
+                        tls_stream.read_exact(&mut buffer).await.unwrap();
+                        query_count += 1;
+                        if query_count > answered_count {
+                            continue;
+                        }
+                        let mut response_message = Message::from_vec(&buffer).unwrap();
+                        response_message.set_message_type(MessageType::Response);
+                        let raw_response_message = response_message.to_vec().unwrap();
+                        let length = u16::try_from(raw_response_message.len()).unwrap();
+                        tls_stream.write_u16(length).await.unwrap();
+                        tls_stream.write_all(&raw_response_message).await.unwrap();
+                        tls_stream.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add(certificate).unwrap();
+        let tls_config = RustlsClientConfig::builder()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        let tls_client = TlsClient {
+            server_name: ServerName::try_from("localhost").unwrap(),
+            socket_addr_list: vec![socket_addr],
+            connector: TlsConnector::from(Arc::new(tls_config)),
+            timeout: Duration::from_millis(500),
+            idle_timeout: None,
+            connection: tokio::sync::Mutex::new(None),
+        };
+
+        let request_message = build_request_message("example.com".parse().unwrap(), RecordType::A);
+        assert!(tls_client.send(&request_message).await.is_ok());
+        sleep(Duration::from_secs(1)).await;
+
+        // the query after the idle period times out, and the connection is closed
+        // so that the next query gets a new one
+        let result = tls_client.send(&request_message).await;
+        assert!(matches!(result, Err(Timeout(..))));
+        sleep(Duration::from_millis(200)).await;
+        let connection = tls_client.connection.lock().await.clone().unwrap();
+        assert!(connection.is_closed());
+
+        let response_message = tls_client.send(&request_message).await.unwrap();
+        assert_eq!(response_message.id(), request_message.id());
+    }
+}
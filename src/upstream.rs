@@ -1,84 +1,1049 @@
 use crate::bootstrap::BootstrapClient;
-use crate::error::UpstreamError::{self, Build, Resolve};
+use crate::error::UpstreamError::{
+    self, BodyRead, Build, Connect, ContentType, InvalidUrl, Malformed, OptionCount, RateLimited,
+    Resolve, Serialize, Status, Timeout,
+};
+#[cfg(feature = "http3")]
+use crate::http3::Http3Client;
+#[cfg(feature = "quic")]
+use crate::quic::QuicClient;
+use crate::resolver::Resolver;
+use crate::strategy::Strategy;
+use crate::tls::TlsClient;
+use crate::utils::{build_request_message, query_name, DNS_MESSAGE_CONTENT_TYPE};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::future::join_all;
 use reqwest::{
-    header::{HeaderMap, HeaderValue, CONTENT_TYPE},
-    Client,
+    header::{HeaderMap, HeaderValue, ACCEPT, AGE, CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER},
+    Client, Method, StatusCode, Url,
+};
+use std::{
+    error::Error,
+    fmt::{Debug, Display},
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant, SystemTime},
 };
-use std::{net::IpAddr, time::Duration};
-use tracing::info;
-use trust_dns_proto::op::message::Message;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
+use trust_dns_proto::{
+    op::message::Message,
+    rr::{Name, Record, RecordType},
+};
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(3600);
+const RTT_SMOOTHING_FACTOR: f64 = 0.3;
+const REBOOTSTRAP_INTERVAL_MIN: Duration = Duration::from_secs(60);
+const REBOOTSTRAP_INTERVAL_MAX: Duration = Duration::from_secs(86400);
+const BODY_EXCERPT_LENGTH: usize = 128;
 
 #[derive(Clone, Debug)]
-pub struct HttpsClient {
-    host: String,
-    port: u16,
-    https_client: Client,
+pub struct ClientConfig {
+    pub timeout: Duration,
+    pub pool_idle_timeout: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub warm_up: bool,
+    #[cfg(feature = "http3")]
+    pub http3: bool,
 }
 
-impl HttpsClient {
-    pub async fn new(host: String, port: u16) -> Result<Self, UpstreamError> {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            CONTENT_TYPE,
-            HeaderValue::from_str("application/dns-message").unwrap(),
-        );
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            timeout: Duration::from_secs(10),
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            keepalive_interval: None,
+            warm_up: false,
+            #[cfg(feature = "http3")]
+            http3: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    failures: u32,
+    unhealthy_until: Option<Instant>,
+    rate_limited_until: Option<Instant>,
+    rtt: Option<Duration>,
+}
 
-        let mut client_builder = Client::builder()
-            .default_headers(headers)
-            .https_only(true)
-            .gzip(true)
-            .brotli(true)
-            .timeout(Duration::from_secs(10));
+impl Health {
+    fn is_healthy(&self) -> bool {
+        match self.unhealthy_until {
+            Some(unhealthy_until) => Instant::now() >= unhealthy_until,
+            None => true,
+        }
+    }
 
-        if host.parse::<IpAddr>().is_err() {
-            let bootstrap_client = match BootstrapClient::new() {
-                Ok(bootstrap_client) => bootstrap_client,
-                Err(error) => return Err(error),
-            };
-            let ip_addr = match bootstrap_client.bootstrap(&host).await {
-                Ok(ip_addr) => ip_addr,
+    fn is_rate_limited(&self) -> bool {
+        match self.rate_limited_until {
+            Some(rate_limited_until) => Instant::now() < rate_limited_until,
+            None => false,
+        }
+    }
+
+    fn record_success(&mut self, rtt: Duration) {
+        self.failures = 0;
+        self.unhealthy_until = None;
+        self.rate_limited_until = None;
+        self.rtt = match self.rtt {
+            Some(average_rtt) => Some(
+                average_rtt.mul_f64(1.0 - RTT_SMOOTHING_FACTOR) + rtt.mul_f64(RTT_SMOOTHING_FACTOR),
+            ),
+            None => Some(rtt),
+        };
+    }
+
+    fn record_failure(&mut self, retry_after: Option<Duration>) {
+        self.failures = self.failures.saturating_add(1);
+        let backoff = BACKOFF_BASE
+            .saturating_mul(2_u32.saturating_pow(self.failures - 1))
+            .min(BACKOFF_MAX)
+            .max(retry_after.unwrap_or_default());
+        // a panic here would poison the health lock and break the upstream for good,
+        // so the additions are checked even though the Retry-After is capped
+        let now = Instant::now();
+        self.unhealthy_until = Some(now.checked_add(backoff).unwrap_or(now + RETRY_AFTER_MAX));
+        if let Some(retry_after) = retry_after {
+            self.rate_limited_until = Some(
+                now.checked_add(retry_after)
+                    .unwrap_or(now + RETRY_AFTER_MAX),
+            );
+        }
+    }
+}
+
+#[async_trait]
+pub trait Transport: Debug + Send + Sync {
+    async fn send(&self, request_message: &Message) -> Result<Message, UpstreamError>;
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    url: Url,
+    weight: u32,
+    transport: Arc<RwLock<Arc<dyn Transport>>>,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    pub async fn new(
+        url: Url,
+        weight: u32,
+        method: Method,
+        client_config: &ClientConfig,
+        bootstrap_client: &BootstrapClient,
+    ) -> Result<Self, UpstreamError> {
+        let (url, client_config) = match url.scheme() {
+            "https" => (url, client_config.clone()),
+            #[cfg(feature = "http3")]
+            "h3" => {
+                let client_config = ClientConfig {
+                    http3: true,
+                    ..client_config.clone()
+                };
+                match format!("https{}", &url.as_str()["h3".len()..]).parse() {
+                    Ok(url) => (url, client_config),
+                    Err(_) => return Err(InvalidUrl(url.to_string())),
+                }
+            }
+            "tls" => (url, client_config.clone()),
+            #[cfg(feature = "quic")]
+            "quic" => (url, client_config.clone()),
+            _ => return Err(InvalidUrl(url.to_string())),
+        };
+
+        let mut socket_addr_list = Vec::new();
+        let mut ttl = None;
+        if let Some(host) = domain(&url) {
+            let (bootstrap_addr_list, bootstrap_ttl) = match bootstrap_client.bootstrap(host).await
+            {
+                Ok(bootstrap_result) => bootstrap_result,
                 Err(error) => return Err(error),
             };
-            client_builder = client_builder.resolve(&host, ip_addr);
+            socket_addr_list = bootstrap_addr_list;
+            ttl = Some(bootstrap_ttl);
         }
 
-        let https_client = match client_builder.build() {
-            Ok(https_client) => https_client,
-            Err(_) => return Err(Build),
+        let transport = Arc::new(RwLock::new(build_transport(
+            &url,
+            &method,
+            &socket_addr_list,
+            &client_config,
+        )?));
+        info!("connected to {}", url);
+
+        if let Some(ttl) = ttl {
+            tokio::spawn(rebootstrap(
+                url.clone(),
+                method.clone(),
+                socket_addr_list,
+                ttl,
+                client_config,
+                bootstrap_client.clone(),
+                Arc::downgrade(&transport),
+            ));
+        }
+
+        Ok(Upstream {
+            url,
+            weight,
+            transport,
+            health: Mutex::new(Health::default()),
+        })
+    }
+
+    pub async fn warm_up(&self) {
+        // any query opens the connection, so the root NS query is used as it is
+        // small and cached by every resolver, and it skips the health tracking
+        // so that a failed warm-up doesn't put the upstream in backoff
+        let request_message = build_request_message(Name::root(), RecordType::NS);
+        if self.send(&request_message).await.is_ok() {
+            debug!("warmed up the connection to {}", self.url);
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health.lock().unwrap().is_healthy()
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.health.lock().unwrap().is_rate_limited()
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.health.lock().unwrap().rtt
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    #[cfg(test)]
+    pub fn record_success(&self, rtt: Duration) {
+        self.health.lock().unwrap().record_success(rtt);
+    }
+
+    #[cfg(test)]
+    pub fn record_failure(&self, retry_after: Option<Duration>) {
+        self.health.lock().unwrap().record_failure(retry_after);
+    }
+
+    pub async fn resolve(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        let instant = Instant::now();
+        let result = self.send(request_message).await;
+
+        let mut health = self.health.lock().unwrap();
+        match &result {
+            Ok(_) => health.record_success(instant.elapsed()),
+            Err(error) => {
+                match error.source() {
+                    Some(source) => warn!("{} from {}: {}", error, self.url, source),
+                    None => warn!("{} from {}", error, self.url),
+                }
+                let retry_after = match error {
+                    RateLimited(_, _, retry_after) => *retry_after,
+                    _ => None,
+                };
+                health.record_failure(retry_after);
+            }
+        }
+        result
+    }
+
+    async fn send(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        // the transport is cloned so that the in-flight requests keep their connections
+        // when the transport is replaced after a re-bootstrap
+        let transport = self.transport.read().unwrap().clone();
+        transport.send(request_message).await
+    }
+}
+
+#[derive(Debug)]
+struct HttpsTransport {
+    url: Url,
+    method: Method,
+    https_client: Client,
+    #[cfg(feature = "http3")]
+    http3_client: Option<Arc<Http3Client>>,
+}
+
+impl HttpsTransport {
+    fn new(
+        url: &Url,
+        method: &Method,
+        socket_addr_list: &[SocketAddr],
+        client_config: &ClientConfig,
+    ) -> Result<Self, UpstreamError> {
+        let https_client = build_client(url, socket_addr_list, client_config)?;
+
+        #[cfg(feature = "http3")]
+        let http3_client = match client_config.http3 {
+            true => Some(Arc::new(Http3Client::new(
+                url,
+                socket_addr_list,
+                client_config,
+            )?)),
+            false => None,
         };
-        info!("connected to https://{}:{}", host, port);
 
-        Ok(HttpsClient {
-            host,
-            port,
+        Ok(HttpsTransport {
+            url: url.clone(),
+            method: method.clone(),
             https_client,
+            #[cfg(feature = "http3")]
+            http3_client,
         })
     }
 
-    pub async fn process(&mut self, request_message: Message) -> Result<Message, UpstreamError> {
+    fn build_request(
+        &self,
+        request_message: &Message,
+    ) -> Result<(Url, Option<Vec<u8>>), UpstreamError> {
+        if self.method == Method::GET {
+            // RFC 8484 recommends the message ID 0 in GET requests to make the
+            // responses cacheable by the HTTP intermediaries
+            let mut request_message = request_message.clone();
+            request_message.set_id(0);
+            let raw_request_message = match request_message.to_vec() {
+                Ok(raw_request_message) => raw_request_message,
+                Err(error) => return Err(Serialize(query_name(&request_message), error)),
+            };
+
+            let mut url = self.url.clone();
+            url.query_pairs_mut()
+                .append_pair("dns", &URL_SAFE_NO_PAD.encode(raw_request_message));
+            return Ok((url, None));
+        }
 
         let raw_request_message = match request_message.to_vec() {
             Ok(raw_request_message) => raw_request_message,
-            Err(_) => return Err(Resolve),
+            Err(error) => return Err(Serialize(query_name(request_message), error)),
         };
+        Ok((self.url.clone(), Some(raw_request_message)))
+    }
 
-        let url = format!("https://{}:{}/dns-query", self.host, self.port);
-        let request = self.https_client.post(url).body(raw_request_message);
+    async fn send_http(
+        &self,
+        name: &str,
+        url: Url,
+        body: Option<Vec<u8>>,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), UpstreamError> {
+        #[cfg(feature = "http3")]
+        if let Some(http3_client) = self.http3_client.as_ref() {
+            if http3_client.is_available() {
+                match http3_client.send(name, url.clone(), body.clone()).await {
+                    Ok(response) => return Ok(response),
+                    Err(error) => match error.source() {
+                        Some(source) => warn!(
+                            "{} from {}: {}, falling back to HTTP/2",
+                            error, self.url, source
+                        ),
+                        None => warn!("{} from {}, falling back to HTTP/2", error, self.url),
+                    },
+                }
+            }
+        }
+
+        let request = match body {
+            Some(body) => self
+                .https_client
+                .post(url)
+                .header(CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+                .body(body),
+            None => self.https_client.get(url),
+        };
         let response = match request.send().await {
             Ok(response) => response,
-            Err(_) => return Err(Resolve),
+            Err(error) if error.is_timeout() => {
+                return Err(Timeout(name.to_string(), error.into()))
+            }
+            Err(error) => return Err(Connect(name.to_string(), error)),
         };
 
-        let raw_response_message = match response.bytes().await {
-            Ok(response_bytes) => response_bytes,
-            Err(_) => return Err(Resolve),
-        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        match response.bytes().await {
+            Ok(response_bytes) => Ok((status, headers, response_bytes.to_vec())),
+            Err(error) if error.is_timeout() => Err(Timeout(name.to_string(), error.into())),
+            Err(error) => Err(BodyRead(name.to_string(), error)),
+        }
+    }
+}
 
-        let message = match Message::from_vec(&raw_response_message) {
+#[async_trait]
+impl Transport for HttpsTransport {
+    async fn send(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        let name = query_name(request_message);
+        let (url, body) = self.build_request(request_message)?;
+        let (status, headers, raw_response_message) = self.send_http(&name, url, body).await?;
+        validate_response(&name, status, &headers, &raw_response_message)?;
+
+        let mut message = match Message::from_vec(&raw_response_message) {
             Ok(message) => message,
-            Err(_) => return Err(Resolve),
+            Err(error) => return Err(Malformed(name, error)),
         };
 
+        message.set_id(request_message.id());
+        apply_http_freshness(&mut message, &headers);
         Ok(message)
     }
 }
+
+fn build_transport(
+    url: &Url,
+    method: &Method,
+    socket_addr_list: &[SocketAddr],
+    client_config: &ClientConfig,
+) -> Result<Arc<dyn Transport>, UpstreamError> {
+    match url.scheme() {
+        "tls" => Ok(Arc::new(TlsClient::new(
+            url,
+            socket_addr_list,
+            client_config,
+        )?)),
+        #[cfg(feature = "quic")]
+        "quic" => Ok(Arc::new(QuicClient::new(
+            url,
+            socket_addr_list,
+            client_config,
+        )?)),
+        _ => Ok(Arc::new(HttpsTransport::new(
+            url,
+            method,
+            socket_addr_list,
+            client_config,
+        )?)),
+    }
+}
+
+pub fn build_socket_addr_list(
+    url: &Url,
+    socket_addr_list: &[SocketAddr],
+    default_port: u16,
+) -> (String, Vec<SocketAddr>) {
+    let server_name = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(default_port);
+
+    // the upstreams with an IP host aren't bootstrapped, so the host is the only address
+    let socket_addr_list = match server_name.parse::<IpAddr>() {
+        Ok(ip_address) => vec![SocketAddr::new(ip_address, port)],
+        Err(_) => socket_addr_list
+            .iter()
+            .map(|socket_addr| SocketAddr::new(socket_addr.ip(), port))
+            .collect(),
+    };
+    (server_name, socket_addr_list)
+}
+
+pub async fn connect_socket_addr_list<T, E, F, Fut>(
+    socket_addr_list: &[SocketAddr],
+    connect_timeout: Duration,
+    protocol: &str,
+    mut connect_addr: F,
+) -> Result<T, E>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display + From<io::Error>,
+{
+    // each address gets a share of the timeout, so that a blackholed address
+    // doesn't use up the whole timeout before the next one is tried
+    let addr_count = u32::try_from(socket_addr_list.len()).unwrap_or(u32::MAX);
+    let connect_timeout = connect_timeout / addr_count.max(1);
+
+    let mut last_error = E::from(io::ErrorKind::AddrNotAvailable.into());
+    for socket_addr in socket_addr_list {
+        let error = match timeout(connect_timeout, connect_addr(*socket_addr)).await {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(error)) => error,
+            Err(_) => E::from(io::ErrorKind::TimedOut.into()),
+        };
+        warn!(
+            "failed to connect to {} over {}: {}",
+            socket_addr, protocol, error
+        );
+        last_error = error;
+    }
+    Err(last_error)
+}
+
+// the IPv4 hosts of the URLs with a non-special scheme such as tls:// are
+// parsed as domains, so they are filtered out to skip the bootstrap
+fn domain(url: &Url) -> Option<&str> {
+    url.domain()
+        .filter(|host| host.parse::<Ipv4Addr>().is_err())
+}
+
+fn build_client(
+    url: &Url,
+    socket_addr_list: &[SocketAddr],
+    client_config: &ClientConfig,
+) -> Result<Client, UpstreamError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ACCEPT,
+        HeaderValue::from_str(DNS_MESSAGE_CONTENT_TYPE).unwrap(),
+    );
+
+    let mut client_builder = Client::builder()
+        .default_headers(headers)
+        .https_only(true)
+        .gzip(true)
+        .brotli(true)
+        .timeout(client_config.timeout)
+        .connect_timeout(client_config.timeout)
+        .pool_idle_timeout(client_config.pool_idle_timeout);
+
+    // the pings keep the idle HTTP/2 connections open, so the queries after an
+    // idle period don't pay for a new TCP and TLS handshake
+    if let Some(keepalive_interval) = client_config.keepalive_interval {
+        client_builder = client_builder
+            .http2_keep_alive_interval(keepalive_interval)
+            .http2_keep_alive_timeout(client_config.timeout)
+            .http2_keep_alive_while_idle(true);
+    }
+
+    if let Some(host) = url.domain() {
+        // the connector tries the addresses happy eyeballs style
+        client_builder = client_builder.resolve_to_addrs(host, socket_addr_list);
+    }
+
+    match client_builder.build() {
+        Ok(https_client) => Ok(https_client),
+        Err(_) => Err(Build),
+    }
+}
+
+async fn rebootstrap(
+    url: Url,
+    method: Method,
+    mut socket_addr_list: Vec<SocketAddr>,
+    mut ttl: Duration,
+    client_config: ClientConfig,
+    bootstrap_client: BootstrapClient,
+    transport: Weak<RwLock<Arc<dyn Transport>>>,
+) {
+    let host = match domain(&url) {
+        Some(host) => host,
+        None => return,
+    };
+
+    loop {
+        sleep(ttl.clamp(REBOOTSTRAP_INTERVAL_MIN, REBOOTSTRAP_INTERVAL_MAX)).await;
+        if transport.strong_count() == 0 {
+            return;
+        }
+
+        let (bootstrap_addr_list, bootstrap_ttl) = match bootstrap_client.bootstrap(host).await {
+            Ok(bootstrap_result) => bootstrap_result,
+            Err(error) => {
+                warn!("{}, keeping the previous addresses", error);
+                ttl = REBOOTSTRAP_INTERVAL_MIN;
+                continue;
+            }
+        };
+        ttl = bootstrap_ttl;
+
+        let mut previous_addr_list = socket_addr_list.clone();
+        let mut current_addr_list = bootstrap_addr_list.clone();
+        previous_addr_list.sort();
+        current_addr_list.sort();
+        if previous_addr_list == current_addr_list {
+            continue;
+        }
+
+        let new_transport =
+            match build_transport(&url, &method, &bootstrap_addr_list, &client_config) {
+                Ok(new_transport) => new_transport,
+                Err(error) => {
+                    warn!("{}", error);
+                    continue;
+                }
+            };
+        let transport = match transport.upgrade() {
+            Some(transport) => transport,
+            None => return,
+        };
+        *transport.write().unwrap() = new_transport;
+        info!("re-bootstrapped {} to {:?}", url, bootstrap_addr_list);
+        socket_addr_list = bootstrap_addr_list;
+    }
+}
+
+fn validate_response(
+    name: &str,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(), UpstreamError> {
+    if !status.is_success() {
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|retry_after| retry_after.to_str().ok())
+            .and_then(parse_retry_after);
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(RateLimited(
+                name.to_string(),
+                body_excerpt(body),
+                retry_after,
+            ));
+        }
+        return Err(Status(name.to_string(), status, body_excerpt(body)));
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !media_type.eq_ignore_ascii_case(DNS_MESSAGE_CONTENT_TYPE) {
+        return Err(ContentType(
+            name.to_string(),
+            content_type,
+            body_excerpt(body),
+        ));
+    }
+
+    Ok(())
+}
+
+fn body_excerpt(body: &[u8]) -> String {
+    let excerpt = String::from_utf8_lossy(&body[..body.len().min(BODY_EXCERPT_LENGTH)]);
+    excerpt.trim().to_string()
+}
+
+fn parse_retry_after(retry_after: &str) -> Option<Duration> {
+    if let Ok(seconds) = retry_after.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds).min(RETRY_AFTER_MAX));
+    }
+
+    let retry_time = httpdate::parse_http_date(retry_after.trim()).ok()?;
+    Some(
+        retry_time
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .min(RETRY_AFTER_MAX),
+    )
+}
+
+// RFC 8484 requires the DNS TTLs to account for the Age header and not to
+// exceed the HTTP freshness lifetime of the response
+fn apply_http_freshness(message: &mut Message, headers: &HeaderMap) {
+    let age = headers
+        .get(AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.trim().parse::<u32>().ok())
+        .unwrap_or_default();
+
+    let max_age = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|cache_control| cache_control.to_str().ok())
+        .flat_map(|cache_control| cache_control.split(','))
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|max_age| max_age.parse::<u32>().ok());
+
+    if age == 0 && max_age.is_none() {
+        return;
+    }
+
+    let apply_freshness = |record_list: &mut Vec<Record>| {
+        for record in record_list {
+            let mut ttl = record.ttl().saturating_sub(age);
+            if let Some(max_age) = max_age {
+                ttl = ttl.min(max_age.saturating_sub(age));
+            }
+            record.set_ttl(ttl);
+        }
+    };
+    apply_freshness(message.answers_mut());
+    apply_freshness(message.name_servers_mut());
+    apply_freshness(message.additionals_mut());
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpsClient {
+    upstream_list: Arc<Vec<Upstream>>,
+    strategy: Arc<dyn Strategy>,
+}
+
+impl HttpsClient {
+    pub async fn new(
+        url_list: Vec<Url>,
+        weight_list: Vec<u32>,
+        method_list: Vec<Method>,
+        client_config: ClientConfig,
+        bootstrap_client: BootstrapClient,
+        strategy: Arc<dyn Strategy>,
+    ) -> Result<Self, UpstreamError> {
+        // the options are matched to the upstreams by their order, so a missing
+        // or extra value would silently change the routing
+        if !weight_list.is_empty() && weight_list.len() != url_list.len() {
+            return Err(OptionCount(
+                String::from("--upstream-weight"),
+                weight_list.len(),
+                url_list.len(),
+            ));
+        }
+        if !method_list.is_empty() && method_list.len() != url_list.len() {
+            return Err(OptionCount(
+                String::from("--upstream-method"),
+                method_list.len(),
+                url_list.len(),
+            ));
+        }
+
+        let mut upstream_list = Vec::new();
+        for (index, url) in url_list.into_iter().enumerate() {
+            let weight = weight_list.get(index).copied().unwrap_or(1);
+            let method = method_list.get(index).cloned().unwrap_or(Method::POST);
+            let upstream =
+                Upstream::new(url, weight, method, &client_config, &bootstrap_client).await?;
+            upstream_list.push(upstream);
+        }
+        let upstream_list = Arc::new(upstream_list);
+
+        if client_config.warm_up {
+            let upstream_list = upstream_list.clone();
+            tokio::spawn(async move {
+                join_all(upstream_list.iter().map(|upstream| upstream.warm_up())).await;
+            });
+        }
+
+        Ok(HttpsClient {
+            upstream_list,
+            strategy,
+        })
+    }
+}
+
+#[async_trait]
+impl Resolver for HttpsClient {
+    async fn resolve(&self, request_message: &Message) -> Result<Message, UpstreamError> {
+        if self.upstream_list.is_empty() {
+            return Err(Resolve(query_name(request_message)));
+        }
+        self.strategy
+            .resolve(&self.upstream_list, request_message)
+            .await
+    }
+
+    fn is_available(&self) -> bool {
+        self.upstream_list
+            .iter()
+            .any(|upstream| upstream.is_healthy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_http_freshness, build_socket_addr_list, parse_retry_after, ClientConfig, Health,
+        HttpsClient, HttpsTransport, Upstream, BACKOFF_BASE, BACKOFF_MAX, RETRY_AFTER_MAX,
+    };
+    use crate::bootstrap::BootstrapClient;
+    #[cfg(feature = "http3")]
+    use crate::error::UpstreamError::Connect;
+    use crate::error::UpstreamError::{InvalidUrl, OptionCount};
+    use crate::strategy::Failover;
+    use crate::utils::build_request_message;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    #[cfg(feature = "http3")]
+    use reqwest::Url;
+    use reqwest::{
+        header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL},
+        Method,
+    };
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    };
+    use trust_dns_proto::{
+        op::Message,
+        rr::{Name, RData, Record, RecordType},
+    };
+
+    fn build_response_message(ttl: u32) -> Message {
+        let name: Name = "example.com".parse().unwrap();
+        let mut answer = Record::with(name, RecordType::A, ttl);
+        answer.set_data(Some(RData::A(Ipv4Addr::new(1, 1, 1, 1))));
+
+        let mut response_message = Message::new();
+        response_message.add_answer(answer);
+        response_message
+    }
+
+    #[test]
+    fn test_http_freshness_age() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AGE, HeaderValue::from_static("100"));
+
+        let mut response_message = build_response_message(300);
+        apply_http_freshness(&mut response_message, &headers);
+        assert_eq!(response_message.answers()[0].ttl(), 200);
+    }
+
+    #[test]
+    fn test_http_freshness_max_age() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AGE, HeaderValue::from_static("20"));
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=60"),
+        );
+
+        let mut response_message = build_response_message(300);
+        apply_http_freshness(&mut response_message, &headers);
+        assert_eq!(response_message.answers()[0].ttl(), 40);
+
+        let mut response_message = build_response_message(30);
+        apply_http_freshness(&mut response_message, &headers);
+        assert_eq!(response_message.answers()[0].ttl(), 10);
+    }
+
+    #[cfg(feature = "http3")]
+    #[tokio::test]
+    async fn test_upstream_http3_url() {
+        let upstream = Upstream::new(
+            "h3://1.1.1.1/dns-query".parse().unwrap(),
+            1,
+            Method::POST,
+            &ClientConfig::default(),
+            &BootstrapClient::new(Vec::new()).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(upstream.url.as_str(), "https://1.1.1.1/dns-query");
+    }
+
+    #[cfg(feature = "http3")]
+    #[tokio::test]
+    async fn test_https_transport_http3_fallback() {
+        // the UDP socket drops the QUIC handshake, and nothing listens on the TCP
+        // port, so the HTTP/2 fallback fails to connect right away
+        let udp_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = format!(
+            "https://127.0.0.1:{}/dns-query",
+            udp_socket.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        let client_config = ClientConfig {
+            http3: true,
+            ..ClientConfig::default()
+        };
+        let transport = HttpsTransport::new(&url, &Method::POST, &[], &client_config).unwrap();
+
+        let instant = Instant::now();
+        let result = transport
+            .send_http("example.com", url.clone(), Some(Vec::new()))
+            .await;
+        assert!(matches!(result, Err(Connect(..))));
+        assert!(instant.elapsed() < Duration::from_secs(5));
+
+        // HTTP/3 isn't tried again until the retry interval elapses
+        let http3_client = transport.http3_client.as_ref().unwrap();
+        assert!(!http3_client.is_available());
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn test_upstream_quic_url() {
+        // the IPv4 host must not be bootstrapped, which would fail without any
+        // bootstrap server
+        let upstream = Upstream::new(
+            "quic://94.140.14.14".parse().unwrap(),
+            1,
+            Method::POST,
+            &ClientConfig::default(),
+            &BootstrapClient::new(Vec::new()).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(upstream.url.as_str(), "quic://94.140.14.14");
+    }
+
+    #[test]
+    fn test_https_transport_get() {
+        let https_transport = HttpsTransport::new(
+            &"https://1.1.1.1/dns-query".parse().unwrap(),
+            &Method::GET,
+            &[],
+            &ClientConfig::default(),
+        )
+        .unwrap();
+
+        let request_name: Name = "example.com".parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        let (url, body) = https_transport.build_request(&request_message).unwrap();
+        assert!(body.is_none());
+
+        let (key, value) = url.query_pairs().next().unwrap();
+        assert_eq!(key, "dns");
+        let raw_request_message = URL_SAFE_NO_PAD.decode(value.as_bytes()).unwrap();
+        let message = Message::from_vec(&raw_request_message).unwrap();
+        assert_eq!(message.id(), 0);
+        assert_eq!(message.queries(), request_message.queries());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_url() {
+        let upstream = Upstream::new(
+            "https://1.1.1.1:8443/dns-query/client-id?key=value"
+                .parse()
+                .unwrap(),
+            1,
+            Method::POST,
+            &ClientConfig::default(),
+            &BootstrapClient::new(Vec::new()).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            upstream.url.as_str(),
+            "https://1.1.1.1:8443/dns-query/client-id?key=value"
+        );
+
+        let result = Upstream::new(
+            "http://1.1.1.1/dns-query".parse().unwrap(),
+            1,
+            Method::POST,
+            &ClientConfig::default(),
+            &BootstrapClient::new(Vec::new()).unwrap(),
+        )
+        .await;
+        assert!(matches!(result, Err(InvalidUrl(_))));
+    }
+
+    #[test]
+    fn test_socket_addr_list() {
+        let url = "tls://1.1.1.1".parse().unwrap();
+        let (server_name, socket_addr_list) = build_socket_addr_list(&url, &[], 853);
+        assert_eq!(server_name, "1.1.1.1");
+        assert_eq!(socket_addr_list, vec!["1.1.1.1:853".parse().unwrap()]);
+
+        let url = "https://dns.google/dns-query".parse().unwrap();
+        let bootstrap_addr_list: Vec<SocketAddr> = vec![
+            "8.8.8.8:0".parse().unwrap(),
+            "[2001:4860:4860::8888]:0".parse().unwrap(),
+        ];
+        let (server_name, socket_addr_list) =
+            build_socket_addr_list(&url, &bootstrap_addr_list, 853);
+        assert_eq!(server_name, "dns.google");
+        assert_eq!(
+            socket_addr_list,
+            vec![
+                "8.8.8.8:443".parse::<SocketAddr>().unwrap(),
+                "[2001:4860:4860::8888]:443".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_health_backoff() {
+        let mut health = Health::default();
+        assert!(health.is_healthy());
+
+        health.record_failure(None);
+        assert!(!health.is_healthy());
+        let unhealthy_until = health.unhealthy_until.unwrap();
+        assert!(unhealthy_until <= Instant::now() + BACKOFF_BASE);
+
+        for _ in 0..16 {
+            health.record_failure(None);
+        }
+        let unhealthy_until = health.unhealthy_until.unwrap();
+        assert!(unhealthy_until <= Instant::now() + BACKOFF_MAX);
+        assert!(unhealthy_until > Instant::now() + BACKOFF_MAX / 2);
+
+        health.record_success(Duration::from_millis(100));
+        assert!(health.is_healthy());
+    }
+
+    #[test]
+    fn test_health_retry_after() {
+        let mut health = Health::default();
+        health.record_failure(Some(Duration::from_secs(120)));
+        let unhealthy_until = health.unhealthy_until.unwrap();
+        assert!(unhealthy_until > Instant::now() + Duration::from_secs(60));
+        assert!(health.is_rate_limited());
+
+        health.record_failure(Some(Duration::MAX));
+        assert!(health.is_rate_limited());
+
+        health.record_success(Duration::from_millis(100));
+        assert!(!health.is_rate_limited());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("invalid"), None);
+        assert_eq!(
+            parse_retry_after("10000000000000000000"),
+            Some(RETRY_AFTER_MAX)
+        );
+
+        let retry_time = SystemTime::now() + Duration::from_secs(300);
+        let retry_after = parse_retry_after(&httpdate::fmt_http_date(retry_time)).unwrap();
+        assert!(retry_after > Duration::from_secs(290) && retry_after <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_health_rtt() {
+        let mut health = Health::default();
+        assert_eq!(health.rtt, None);
+
+        health.record_success(Duration::from_millis(100));
+        assert_eq!(health.rtt, Some(Duration::from_millis(100)));
+
+        health.record_success(Duration::from_millis(200));
+        let rtt = health.rtt.unwrap();
+        assert!(rtt > Duration::from_millis(129) && rtt < Duration::from_millis(131));
+    }
+
+    #[tokio::test]
+    async fn test_https_client_option_count() {
+        let url_list = vec![
+            "https://1.1.1.1/dns-query".parse().unwrap(),
+            "https://8.8.8.8/dns-query".parse().unwrap(),
+        ];
+        let result = HttpsClient::new(
+            url_list,
+            vec![1],
+            Vec::new(),
+            ClientConfig::default(),
+            BootstrapClient::new(Vec::new()).unwrap(),
+            Arc::new(Failover),
+        )
+        .await;
+        assert!(matches!(result, Err(OptionCount(_, 1, 2))));
+
+        let url_list = vec!["https://1.1.1.1/dns-query".parse().unwrap()];
+        let result = HttpsClient::new(
+            url_list,
+            Vec::new(),
+            vec![Method::GET, Method::POST],
+            ClientConfig::default(),
+            BootstrapClient::new(Vec::new()).unwrap(),
+            Arc::new(Failover),
+        )
+        .await;
+        assert!(matches!(result, Err(OptionCount(_, 2, 1))));
+    }
+}
@@ -1,11 +1,26 @@
-use crate::local::UdpListener;
-use crate::upstream::HttpsClient;
+use crate::bootstrap::BootstrapClient;
+use crate::cache::Cache;
+use crate::error::UpstreamError;
+use crate::local::{HttpsListener, TcpListener, TlsListener, UdpListener};
+use crate::resolver::{CacheLayer, LogLayer, Resolver};
+use crate::strategy::Failover;
+use crate::upstream::{ClientConfig, HttpsClient};
 use rand::{thread_rng, Rng};
+use std::{sync::Arc, time::Duration};
 use trust_dns_proto::{
-    op::{Message, MessageType, Query},
-    rr::{Name, RecordType},
+    error::ProtoError,
+    op::{Edns, Message, MessageType, Query, ResponseCode},
+    rr::{rdata::opt::EdnsOption, Name, RecordType},
 };
 
+const HEADER_LENGTH: usize = 12;
+const EXTENDED_DNS_ERROR_CODE: u16 = 15;
+const OTHER_ERROR: u16 = 0;
+const NO_REACHABLE_AUTHORITY: u16 = 22;
+const NETWORK_ERROR: u16 = 23;
+
+pub const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
 pub fn build_request_message(name: Name, record_type: RecordType) -> Message {
     let mut request_message = Message::new();
 
@@ -21,17 +36,302 @@ pub fn build_request_message(name: Name, record_type: RecordType) -> Message {
     request_message
 }
 
+pub fn query_name(message: &Message) -> String {
+    match message.queries().first() {
+        Some(query) => query.name().to_string(),
+        None => String::from("<empty>"),
+    }
+}
+
+pub fn build_udp_response(
+    response_message: &Message,
+    max_payload: u16,
+) -> Result<Vec<u8>, ProtoError> {
+    let raw_response_message = response_message.to_vec()?;
+    if raw_response_message.len() <= max_payload.into() {
+        return Ok(raw_response_message);
+    }
+
+    let mut truncated_message = response_message.clone();
+    truncated_message.set_truncated(true);
+    truncated_message.take_additionals();
+    truncated_message.take_name_servers();
+    let answer_list = truncated_message.take_answers();
+
+    let mut raw_truncated_message = truncated_message.to_vec()?;
+    for answer in answer_list {
+        truncated_message.add_answer(answer);
+        let raw_message = truncated_message.to_vec()?;
+        if raw_message.len() > max_payload.into() {
+            break;
+        }
+        raw_truncated_message = raw_message;
+    }
+    Ok(raw_truncated_message)
+}
+
+pub fn build_server_failure_response(request_message: &Message, error: &UpstreamError) -> Message {
+    let mut response_message = Message::new();
+    response_message.set_id(request_message.id());
+    response_message.set_message_type(MessageType::Response);
+    response_message.set_op_code(request_message.op_code());
+    response_message.set_recursion_desired(request_message.recursion_desired());
+    response_message.set_recursion_available(true);
+    response_message.set_response_code(ResponseCode::ServFail);
+    response_message.add_queries(request_message.queries().to_vec());
+
+    // the extended DNS error can only be sent to the clients supporting EDNS
+    if request_message.edns().is_some() {
+        let (info_code, extra_text) = extended_dns_error(error);
+        let mut option_data = info_code.to_be_bytes().to_vec();
+        option_data.extend_from_slice(extra_text.as_bytes());
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(request_message.max_payload());
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(EXTENDED_DNS_ERROR_CODE, option_data));
+        response_message.set_edns(edns);
+    }
+    response_message
+}
+
+// the extra text is fixed for each kind of error, since the error messages
+// might carry the HTTP bodies of the upstream to the clients
+fn extended_dns_error(error: &UpstreamError) -> (u16, &'static str) {
+    match error {
+        UpstreamError::Timeout(..) | UpstreamError::Connect(..) | UpstreamError::Tls(..) => {
+            (NETWORK_ERROR, "network error")
+        }
+        #[cfg(feature = "http3")]
+        UpstreamError::Http3(..) => (NETWORK_ERROR, "network error"),
+        #[cfg(feature = "quic")]
+        UpstreamError::Quic(..) => (NETWORK_ERROR, "network error"),
+        UpstreamError::Resolve(_) => (NO_REACHABLE_AUTHORITY, "no reachable upstream"),
+        UpstreamError::RateLimited(..) => (OTHER_ERROR, "upstream rate limited"),
+        UpstreamError::Status(..)
+        | UpstreamError::ContentType(..)
+        | UpstreamError::BodyRead(..)
+        | UpstreamError::Malformed(..) => (OTHER_ERROR, "invalid upstream response"),
+        _ => (OTHER_ERROR, "upstream error"),
+    }
+}
+
+pub fn build_format_error_response(raw_request_message: &[u8]) -> Option<Message> {
+    // the header must be complete and must not belong to a response, otherwise
+    // replying could start a loop between two servers
+    if raw_request_message.len() < HEADER_LENGTH || raw_request_message[2] & 0x80 != 0 {
+        return None;
+    }
+
+    let mut response_message = Message::new();
+    response_message.set_id(u16::from_be_bytes([
+        raw_request_message[0],
+        raw_request_message[1],
+    ]));
+    response_message.set_message_type(MessageType::Response);
+    response_message.set_response_code(ResponseCode::FormErr);
+    Some(response_message)
+}
+
+#[allow(dead_code)]
+pub async fn build_test_resolver() -> Arc<dyn Resolver> {
+    let upstream = vec!["https://cloudflare-dns.com/dns-query".parse().unwrap()];
+    let bootstrap = vec!["https://1.1.1.1/dns-query".parse().unwrap()];
+
+    let https_client = HttpsClient::new(
+        upstream,
+        Vec::new(),
+        Vec::new(),
+        ClientConfig::default(),
+        BootstrapClient::new(bootstrap).unwrap(),
+        Arc::new(Failover),
+    )
+    .await
+    .unwrap();
+
+    let cache_layer = CacheLayer::new(Arc::new(https_client), Cache::default());
+    Arc::new(LogLayer::new(Arc::new(cache_layer)))
+}
+
 #[allow(dead_code)]
 pub async fn build_test_listener() -> UdpListener {
-    let upstream_address = String::from("cloudflare-dns.com");
-    let upstream_port = 443;
     let local_address = String::from("127.0.0.1");
     let local_port = 10053;
 
-    let https_client = HttpsClient::new(upstream_address, upstream_port)
+    let resolver = build_test_resolver().await;
+    UdpListener::new(local_address, local_port, resolver)
         .await
-        .unwrap();
-    UdpListener::new(local_address, local_port, https_client)
+        .unwrap()
+}
+
+#[allow(dead_code)]
+pub async fn build_test_tcp_listener() -> TcpListener {
+    let local_address = String::from("127.0.0.1");
+    let local_port = 10053;
+
+    let resolver = build_test_resolver().await;
+    TcpListener::new(local_address, local_port, resolver, Duration::from_secs(10))
         .await
         .unwrap()
 }
+
+#[allow(dead_code)]
+pub async fn build_test_https_listener(certificate_path: &str, key_path: &str) -> HttpsListener {
+    let local_address = String::from("127.0.0.1");
+    let local_port = 10443;
+
+    let resolver = build_test_resolver().await;
+    HttpsListener::new(
+        local_address,
+        local_port,
+        resolver,
+        Duration::from_secs(10),
+        certificate_path,
+        key_path,
+    )
+    .await
+    .unwrap()
+}
+
+#[allow(dead_code)]
+pub async fn build_test_tls_listener(certificate_path: &str, key_path: &str) -> TlsListener {
+    let local_address = String::from("127.0.0.1");
+    let local_port = 10853;
+
+    let resolver = build_test_resolver().await;
+    TlsListener::new(
+        local_address,
+        local_port,
+        resolver,
+        Duration::from_secs(10),
+        certificate_path,
+        key_path,
+    )
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_format_error_response, build_server_failure_response, build_udp_response, query_name,
+        EXTENDED_DNS_ERROR_CODE, NETWORK_ERROR, NO_REACHABLE_AUTHORITY, OTHER_ERROR,
+    };
+    use crate::error::UpstreamError::{RateLimited, Resolve, Tls};
+    use std::{io, net::Ipv4Addr};
+    use trust_dns_proto::{
+        op::{Edns, Message, MessageType, Query, ResponseCode},
+        rr::{rdata::opt::EdnsCode, Name, RData, Record, RecordType},
+    };
+
+    fn build_response_message(answer_count: u8) -> Message {
+        let name: Name = "example.com".parse().unwrap();
+        let mut response_message = Message::new();
+        response_message.add_query(Query::query(name.clone(), RecordType::A));
+        for i in 0..answer_count {
+            let mut answer = Record::with(name.clone(), RecordType::A, 300);
+            answer.set_data(Some(RData::A(Ipv4Addr::new(10, 0, 0, i))));
+            response_message.add_answer(answer);
+        }
+        response_message
+    }
+
+    #[test]
+    fn test_udp_response_fit() {
+        let response_message = build_response_message(4);
+        let raw_response_message = build_udp_response(&response_message, 512).unwrap();
+        let message = Message::from_vec(&raw_response_message).unwrap();
+        assert!(!message.truncated());
+        assert_eq!(message.answers().len(), 4);
+    }
+
+    #[test]
+    fn test_udp_response_truncate() {
+        let response_message = build_response_message(64);
+        let raw_response_message = build_udp_response(&response_message, 512).unwrap();
+        assert!(raw_response_message.len() <= 512);
+
+        let message = Message::from_vec(&raw_response_message).unwrap();
+        assert!(message.truncated());
+        assert_eq!(message.queries().len(), 1);
+        assert!(message.answers().len() < 64);
+    }
+
+    #[test]
+    fn test_server_failure_response() {
+        let mut request_message = Message::new();
+        request_message.set_id(1234);
+        request_message.set_recursion_desired(true);
+        request_message.add_query(Query::query("example.com".parse().unwrap(), RecordType::A));
+        request_message.set_edns(Edns::new());
+
+        for (error, info_code, extra_text) in [
+            (
+                Tls(String::from("example.com"), io::ErrorKind::TimedOut.into()),
+                NETWORK_ERROR,
+                &b"network error"[..],
+            ),
+            (
+                Resolve(String::from("example.com")),
+                NO_REACHABLE_AUTHORITY,
+                b"no reachable upstream",
+            ),
+            (
+                RateLimited(
+                    String::from("example.com"),
+                    String::from("<html>captive portal</html>"),
+                    None,
+                ),
+                OTHER_ERROR,
+                b"upstream rate limited",
+            ),
+        ] {
+            let response_message = build_server_failure_response(&request_message, &error);
+            let message = Message::from_vec(&response_message.to_vec().unwrap()).unwrap();
+            assert_eq!(message.id(), 1234);
+            assert_eq!(message.message_type(), MessageType::Response);
+            assert_eq!(message.response_code(), ResponseCode::ServFail);
+            assert_eq!(message.queries(), request_message.queries());
+
+            let option = message
+                .edns()
+                .unwrap()
+                .option(EdnsCode::Unknown(EXTENDED_DNS_ERROR_CODE))
+                .unwrap();
+            let option_data: Vec<u8> = option.into();
+            assert_eq!(option_data[..2], info_code.to_be_bytes());
+            assert_eq!(&option_data[2..], extra_text);
+        }
+    }
+
+    #[test]
+    fn test_server_failure_response_without_edns() {
+        let mut request_message = Message::new();
+        request_message.add_query(Query::query("example.com".parse().unwrap(), RecordType::A));
+
+        let error = Resolve(String::from("example.com"));
+        let response_message = build_server_failure_response(&request_message, &error);
+        assert!(response_message.edns().is_none());
+    }
+
+    #[test]
+    fn test_format_error_response() {
+        let mut raw_request_message = vec![0x04, 0xd2, 0x01, 0x00];
+        raw_request_message.resize(16, 0xff);
+
+        let response_message = build_format_error_response(&raw_request_message).unwrap();
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::FormErr);
+
+        raw_request_message[2] |= 0x80;
+        assert!(build_format_error_response(&raw_request_message).is_none());
+        assert!(build_format_error_response(&[0x04, 0xd2]).is_none());
+    }
+
+    #[test]
+    fn test_query_name() {
+        let response_message = build_response_message(0);
+        assert_eq!(query_name(&response_message), "example.com");
+        assert_eq!(query_name(&Message::new()), "<empty>");
+    }
+}
@@ -0,0 +1,61 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use https_dns::utils::{
+    build_request_message, build_test_https_listener, DNS_MESSAGE_CONTENT_TYPE,
+};
+use reqwest::{header::CONTENT_TYPE, Client};
+use std::{fs, net::Ipv4Addr};
+use tokio::test;
+use trust_dns_proto::{
+    op::Message,
+    rr::{Name, RData, RecordType},
+};
+
+#[test]
+async fn https_listener() {
+    let certified_key =
+        rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let certificate_path = std::env::temp_dir().join("https-dns-certificate.pem");
+    let key_path = std::env::temp_dir().join("https-dns-key.pem");
+    fs::write(&certificate_path, certified_key.cert.pem()).unwrap();
+    fs::write(&key_path, certified_key.key_pair.serialize_pem()).unwrap();
+
+    let https_listener = build_test_https_listener(
+        certificate_path.to_str().unwrap(),
+        key_path.to_str().unwrap(),
+    )
+    .await;
+    tokio::spawn(async move {
+        https_listener.listen().await;
+    });
+
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let request_name: Name = "one.one.one.one".parse().unwrap();
+    let request_message = build_request_message(request_name, RecordType::A);
+    let raw_request_message = request_message.to_vec().unwrap();
+
+    let get_request = client.get(format!(
+        "https://127.0.0.1:10443/dns-query?dns={}",
+        URL_SAFE_NO_PAD.encode(&raw_request_message)
+    ));
+    let post_request = client
+        .post("https://127.0.0.1:10443/dns-query")
+        .header(CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+        .body(raw_request_message);
+
+    for request in [get_request, post_request] {
+        let response = request.send().await.unwrap();
+        assert!(response.status().is_success());
+
+        let raw_response_message = response.bytes().await.unwrap();
+        let response_message = Message::from_vec(&raw_response_message).unwrap();
+        let record_data = &response_message.answers()[0].data().unwrap();
+        if let RData::A(ipv4_address) = record_data {
+            assert!([Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(1, 0, 0, 1)].contains(ipv4_address));
+        } else {
+            panic!("the record type is not A")
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use https_dns::utils::{build_request_message, build_test_tcp_listener};
+use std::{collections::HashMap, net::Ipv4Addr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    test,
+};
+use trust_dns_proto::{
+    op::Message,
+    rr::{Name, RData, RecordType},
+};
+
+#[test]
+async fn tcp_record() {
+    let tcp_listener = build_test_tcp_listener().await;
+    tokio::spawn(async move {
+        tcp_listener.listen().await;
+    });
+
+    let result_map = HashMap::from([
+        (
+            "dns.google",
+            vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+        ),
+        (
+            "one.one.one.one",
+            vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(1, 0, 0, 1)],
+        ),
+    ]);
+
+    let mut tcp_stream = TcpStream::connect("127.0.0.1:10053").await.unwrap();
+    for (host, socket_addr_list) in result_map {
+        let request_name: Name = host.parse().unwrap();
+        let request_message = build_request_message(request_name, RecordType::A);
+        let raw_request_message = request_message.to_vec().unwrap();
+
+        tcp_stream
+            .write_u16(raw_request_message.len() as u16)
+            .await
+            .unwrap();
+        tcp_stream.write_all(&raw_request_message).await.unwrap();
+
+        let length = tcp_stream.read_u16().await.unwrap();
+        let mut buffer = vec![0; length.into()];
+        tcp_stream.read_exact(&mut buffer).await.unwrap();
+
+        let response_message = Message::from_vec(&buffer).unwrap();
+        let record_data = &response_message.answers()[0].data().unwrap();
+        if let RData::A(ipv4_address) = record_data {
+            assert!(socket_addr_list.contains(ipv4_address));
+        } else {
+            panic!("the record type is not A")
+        }
+    }
+}
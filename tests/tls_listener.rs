@@ -0,0 +1,72 @@
+use https_dns::utils::{build_request_message, build_test_tls_listener};
+use std::{fs, net::Ipv4Addr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    test,
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use trust_dns_proto::{
+    op::Message,
+    rr::{Name, RData, RecordType},
+};
+
+#[test]
+async fn tls_listener() {
+    let certified_key =
+        rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let certificate_path = std::env::temp_dir().join("https-dns-tls-certificate.pem");
+    let key_path = std::env::temp_dir().join("https-dns-tls-key.pem");
+    fs::write(&certificate_path, certified_key.cert.pem()).unwrap();
+    fs::write(&key_path, certified_key.key_pair.serialize_pem()).unwrap();
+
+    let tls_listener = build_test_tls_listener(
+        certificate_path.to_str().unwrap(),
+        key_path.to_str().unwrap(),
+    )
+    .await;
+    tokio::spawn(async move {
+        tls_listener.listen().await;
+    });
+
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store
+        .add(certified_key.cert.der().clone())
+        .unwrap();
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+
+    let tcp_stream = TcpStream::connect("127.0.0.1:10853").await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut tls_stream = tls_connector
+        .connect(server_name, tcp_stream)
+        .await
+        .unwrap();
+
+    let request_name: Name = "one.one.one.one".parse().unwrap();
+    let request_message = build_request_message(request_name, RecordType::A);
+    let raw_request_message = request_message.to_vec().unwrap();
+
+    tls_stream
+        .write_u16(raw_request_message.len() as u16)
+        .await
+        .unwrap();
+    tls_stream.write_all(&raw_request_message).await.unwrap();
+
+    let length = tls_stream.read_u16().await.unwrap();
+    let mut buffer = vec![0; length.into()];
+    tls_stream.read_exact(&mut buffer).await.unwrap();
+
+    let response_message = Message::from_vec(&buffer).unwrap();
+    let record_data = &response_message.answers()[0].data().unwrap();
+    if let RData::A(ipv4_address) = record_data {
+        assert!([Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(1, 0, 0, 1)].contains(ipv4_address));
+    } else {
+        panic!("the record type is not A")
+    }
+}